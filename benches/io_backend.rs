@@ -0,0 +1,104 @@
+// Compares the tokio::fs and io_uring segment IO backends.
+//
+//     cargo bench --bench io_backend                      # tokio::fs only
+//     cargo bench --bench io_backend --features io-uring  # both
+//
+// Reads are 4 KiB at random aligned offsets with 64 in flight at once, which is
+// where batched submission should pay off. Appends mirror the segment store:
+// 64 KiB buffered writes followed by one fsync. The read file stays in the page
+// cache after the first pass, so drop caches between runs to measure the device.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    sync::Arc,
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::Rng;
+use tokio::{runtime::Runtime, task::JoinSet};
+
+#[path = "../src2/io_backend.rs"]
+mod io_backend;
+
+use io_backend::{new_backend, IoBackend, IoBackendKind};
+
+const READ_FILE_BYTES: u64 = 64 * 1024 * 1024;
+const READ_BYTES: usize = 4096;
+const CONCURRENT_READS: usize = 64;
+const APPEND_BYTES: usize = 64 * 1024;
+const APPENDS_PER_SYNC: usize = 16;
+const QUEUE_DEPTH: u32 = 256;
+
+fn backends() -> Vec<Arc<dyn IoBackend>> {
+    let mut backends = vec![new_backend(IoBackendKind::TokioFs, QUEUE_DEPTH)];
+    if cfg!(all(target_os = "linux", feature = "io-uring")) {
+        backends.push(new_backend(IoBackendKind::IoUring, QUEUE_DEPTH));
+    }
+    backends
+}
+
+fn random_reads(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let path = std::env::temp_dir().join("trust_bench_reads.seg");
+    fs::write(&path, vec![0x5a; READ_FILE_BYTES as usize]).unwrap();
+    let file = Arc::new(File::open(&path).unwrap());
+
+    let mut group = c.benchmark_group("random_reads");
+    group.throughput(Throughput::Bytes((READ_BYTES * CONCURRENT_READS) as u64));
+    for backend in backends() {
+        group.bench_function(BenchmarkId::from_parameter(backend.name()), |b| {
+            b.to_async(&runtime).iter(|| {
+                let (backend, file) = (backend.clone(), file.clone());
+                async move {
+                    let mut reads = JoinSet::new();
+                    for _ in 0..CONCURRENT_READS {
+                        let block = rand::thread_rng().gen_range(0..READ_FILE_BYTES / READ_BYTES as u64);
+                        let (backend, file) = (backend.clone(), file.clone());
+                        reads.spawn(async move { backend.read_at(file, block * READ_BYTES as u64, READ_BYTES).await });
+                    }
+                    while let Some(read) = reads.join_next().await {
+                        read.unwrap().unwrap();
+                    }
+                }
+            });
+        });
+    }
+    group.finish();
+    fs::remove_file(&path).ok();
+}
+
+fn appends_with_fsync(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let path = std::env::temp_dir().join("trust_bench_appends.seg");
+    let file = Arc::new(
+        OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap(),
+    );
+
+    let mut group = c.benchmark_group("appends_with_fsync");
+    group.throughput(Throughput::Bytes((APPEND_BYTES * APPENDS_PER_SYNC) as u64));
+    for backend in backends() {
+        group.bench_function(BenchmarkId::from_parameter(backend.name()), |b| {
+            b.to_async(&runtime).iter(|| {
+                let (backend, file) = (backend.clone(), file.clone());
+                async move {
+                    for append in 0..APPENDS_PER_SYNC {
+                        let offset = (append * APPEND_BYTES) as u64;
+                        backend.write_at(file.clone(), offset, vec![0xa5; APPEND_BYTES]).await.unwrap();
+                    }
+                    backend.sync_data(file).await.unwrap();
+                }
+            });
+        });
+    }
+    group.finish();
+    fs::remove_file(&path).ok();
+}
+
+criterion_group!(benches, random_reads, appends_with_fsync);
+criterion_main!(benches);
@@ -1,37 +1,123 @@
 use std::{
-    collections::{BinaryHeap, HashMap},
+    env,
     fs,
-    io,
+    io::{self, Write},
+    mem,
     path::{Path, PathBuf},
-    sync::Arc,
-    time::{Duration, Instant},
-    cmp::Ordering,
+    sync::{atomic::AtomicU64, Arc, MutexGuard},
+    time::Duration,
 };
 
-use aes_gcm::{aead::Aead, Aes256Gcm};
-use flate2::{read::GzDecoder, write::GzEncoder, Compression};
-use rand::{rngs::OsRng, RngCore};
-use serde::{Deserialize, Serialize};
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncWriteExt},
-    sync::{Mutex, Semaphore},
-};
-use std::time::Duration;
+use bytes::Bytes;
+use chrono::Utc;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+#[path = "src2/atomic_write.rs"]
+mod atomic_write;
+#[path = "src2/audit_log.rs"]
+mod audit_log;
+#[path = "src2/backup_and_recovery.rs"]
+mod backup_and_recovery;
+#[path = "src2/backup_encryption.rs"]
+mod backup_encryption;
+#[path = "src2/compaction.rs"]
+mod compaction;
+#[path = "src2/compression.rs"]
+mod compression;
+#[path = "src2/encryption_service.rs"]
+mod encryption_service;
+#[path = "src2/enhanced_storage_management.rs"]
+mod enhanced_storage_management;
+#[path = "src2/envelope.rs"]
+mod envelope;
+#[path = "src2/eviction_policy.rs"]
+mod eviction_policy;
+#[path = "src2/integrity.rs"]
+mod integrity;
+#[path = "src2/io_backend.rs"]
+mod io_backend;
+#[path = "src2/key_material.rs"]
+mod key_material;
+#[path = "src2/key_rotation.rs"]
+mod key_rotation;
+#[path = "src2/mapped_segments.rs"]
+mod mapped_segments;
+#[path = "src2/master_key.rs"]
+mod master_key;
+#[path = "src2/segment_store.rs"]
+mod segment_store;
+#[path = "src2/sharded_map.rs"]
+mod sharded_map;
+#[path = "src2/storage_management.rs"]
+mod storage_management;
+#[path = "src2/tenants.rs"]
+mod tenants;
+#[path = "src2/write_ahead_log.rs"]
+mod write_ahead_log;
+
+use audit_log::{AuditAction, AuditLog};
+use backup_and_recovery::periodic_backup;
+use compaction::{periodic_compaction, Compaction};
+use compression::{Codec, CompressionSettings};
+use encryption_service::{EncryptionService, EntryContext};
+use envelope::Algorithm;
+use eviction_policy::{new_policy, BufferedPolicy, EvictionPolicy};
+use integrity::{Quarantine, VerificationReport};
+use io_backend::{new_backend, IoBackendKind};
+use key_rotation::{periodic_master_key_refresh, periodic_reencryption};
+use mapped_segments::ValueGuard;
+use master_key::{CachingProvider, KeySource};
+use segment_store::SegmentStore;
+use sharded_map::ShardedEntries;
+use storage_management::{periodic_cleanup, CacheEvictionPolicy, CacheMetrics};
+use tenants::{check_shared_key, check_wrapped_by};
+use write_ahead_log::{FsyncPolicy, WalRecord, WriteAheadLog};
 
 const CACHE_DIR: &str = "cache_dir";
 const CONFIG_FILE: &str = "config.json";
-const ENCRYPTION_KEY_FILE: &str = "encryption_key.bin";
-const CACHE_FILE: &str = "cache.json.gz";
-const BACKUP_FILE: &str = "cache_backup.json.gz";
+const SEGMENT_DIR: &str = "segments";
+pub(crate) const BACKUP_DIR: &str = "segments_backup";
+const QUARANTINE_DIR: &str = "quarantine";
+const WAL_FILE: &str = "wal.log";
+const MAX_SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+const DEFAULT_EXPIRY_SWEEP_SECS: u64 = 60;
+const DEFAULT_MEMORY_CAPACITY_BYTES: u64 = 256 * 1024 * 1024;
+const DEFAULT_DISK_CAPACITY_BYTES: u64 = 16 * 1024 * 1024 * 1024;
+// Typical entry size used to size ARC/2Q ghost lists and the W-TinyLFU sketch from a byte budget
+const POLICY_SIZING_ENTRY_BYTES: u64 = 4096;
+const DEFAULT_MAP_SHARDS: usize = 64;
+const DEFAULT_MMAP_MIN_VALUE_BYTES: u32 = 64 * 1024;
+const DEFAULT_IO_QUEUE_DEPTH: u32 = 256;
+const DEFAULT_COMPACTION_INTERVAL_SECS: u64 = 300;
+const DEFAULT_COMPACTION_GARBAGE_RATIO: f64 = 0.5;
+const DEFAULT_COMPACTION_MAX_BYTES_PER_SEC: u64 = 32 * 1024 * 1024;
+const DEFAULT_REENCRYPTION_INTERVAL_SECS: u64 = 3600;
+const DEFAULT_NAMESPACE: &str = "default";
+const DEFAULT_KEY_DIR: &str = "cache_keys"; // Kept out of the cache directory and its backups
+const DEFAULT_COMPRESSION_MIN_BYTES: usize = 512;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct CacheEntry {
-    value: String,
-    expiry: Option<Instant>,
+    value: Bytes, // Stored form, compressed and encrypted or checksummed; cheap to clone out of a shard
+    expiry: Option<i64>, // Absolute Unix time in milliseconds, so it survives restarts
     access_count: usize,
 }
 
+impl CacheEntry {
+    fn is_expired(&self) -> bool {
+        self.expiry.is_some_and(|expiry| expiry <= Utc::now().timestamp_millis())
+    }
+
+    // Resident memory charged to this entry: key, stored value (including any
+    // encryption or checksum overhead), the entry struct itself and its slot in the map.
+    fn memory_size(&self, key: &str) -> u64 {
+        (key.len() + self.value.len() + mem::size_of::<CacheEntry>() + mem::size_of::<String>()) as u64
+    }
+}
+
+// The payloads are only read through Debug, when `main` returns the error.
+#[allow(dead_code)]
 #[derive(Debug)]
 enum CacheError {
     CleanupError(String),
@@ -42,206 +128,480 @@ enum CacheError {
     SerializationError(serde_json::Error),
     DeserializationError(serde_json::Error),
     IntegrityError,
+    Utf8Error,
+    KeyError(String),
 }
 
+// Two tiers: `map` is the hot in-memory tier, `store` the on-disk tier that holds
+// every live entry. Each tier has its own byte budget and eviction policy.
 struct DiskCache {
-    map: Mutex<HashMap<String, CacheEntry>>,
-    write_semaphore: Semaphore,
-    encryption_enabled: bool,
+    map: ShardedEntries,
+    store: SegmentStore,
+    disk_eviction: BufferedPolicy, // Shared by all shards, so reads only buffer their accesses
+    metrics: CacheMetrics,
+    compaction: Compaction,
+    quarantine: Quarantine,
+    audit: Option<Arc<AuditLog>>,
+    wal: Option<Arc<WriteAheadLog>>,
+    checkpoint_lock: RwLock<()>, // Held shared by writers, exclusively while checkpointing the WAL
+    eviction_policy: CacheEvictionPolicy,
     cache_dir: PathBuf,
-    max_cache_size: usize,
-    encryption_key: Option<Vec<u8>>,
+    namespace: String, // Authenticated with every encrypted value
+    memory_capacity_bytes: u64,
+    disk_capacity_bytes: u64,
+    backup_bytes: AtomicU64, // Size of the segment snapshot and backup archive, counted against the disk budget
+    mmap_min_value_bytes: u32,
+    verify_on_load: bool,
+    load_report: Option<VerificationReport>, // What `verify_on_load` found when the cache was opened
+    compression: CompressionSettings,
+    encryption: Option<EncryptionService>,
+    backup_recipients: Vec<age::x25519::Recipient>,
+    backup_identity_file: Option<PathBuf>, // Only read while restoring
 }
 
 impl DiskCache {
-    async fn new(cache_dir: &str, initial_cache_size: usize, encryption_enabled: bool) -> Result<Self, CacheError> {
+    async fn new(cache_dir: &str, config: &Config) -> Result<Self, CacheError> {
         let cache_dir = Path::new(cache_dir).to_path_buf();
-        fs::create_dir_all(&cache_dir).map_err(|e| CacheError::IoError(e))?;
+        fs::create_dir_all(&cache_dir).map_err(CacheError::IoError)?;
 
-        let map = Mutex::new(HashMap::new());
-        let write_semaphore = Semaphore::new(initial_cache_size);
-        let encryption_key = if encryption_enabled {
-            Some(Self::load_or_generate_encryption_key().await?)
+        let map = ShardedEntries::new(config.map_shards, || {
+            new_policy(
+                config.eviction_policy,
+                policy_sizing_hint(config.memory_capacity_bytes, config.map_shards),
+            )
+        });
+        let disk_eviction = BufferedPolicy::new(
+            new_policy(config.eviction_policy, policy_sizing_hint(config.disk_capacity_bytes, 1)),
+            config.map_shards,
+        );
+        let io = new_backend(config.io_backend, config.io_queue_depth);
+        let store = SegmentStore::open(&cache_dir.join(SEGMENT_DIR), MAX_SEGMENT_BYTES, io)
+            .await
+            .map_err(CacheError::IoError)?;
+        let wal = if config.wal_enabled {
+            Some(WriteAheadLog::open(&cache_dir.join(WAL_FILE), config.wal_fsync).await.map_err(CacheError::IoError)?)
+        } else {
+            None
+        };
+        let backup_recipients = backup_encryption::parse_recipients(&config.backup_recipients)?;
+        let quarantine = Quarantine::open(&cache_dir.join(QUARANTINE_DIR))
+            .await
+            .map_err(CacheError::IoError)?;
+        let audit = if config.audit_log_enabled {
+            Some(AuditLog::open(&cache_dir, config.audit_actor.clone()).await.map_err(CacheError::IoError)?)
+        } else {
+            None
+        };
+        let encryption = if config.encryption_enabled {
+            Some(
+                EncryptionService::open(
+                    &config.key_dir,
+                    Box::new(CachingProvider::new(
+                        config.master_key.provider(&config.key_dir),
+                        Duration::from_secs(config.master_key_refresh_secs),
+                    )),
+                    config.encryption_algorithm,
+                )
+                .await?,
+            )
         } else {
             None
         };
 
-        let disk_cache = DiskCache {
+        let mut disk_cache = DiskCache {
             map,
-            write_semaphore,
-            encryption_enabled,
+            store,
+            disk_eviction,
+            metrics: CacheMetrics::default(),
+            compaction: Compaction::new(config.compaction_garbage_ratio, config.compaction_max_bytes_per_sec),
+            quarantine,
+            audit,
+            wal,
+            checkpoint_lock: RwLock::new(()),
+            eviction_policy: config.eviction_policy,
             cache_dir: cache_dir.clone(),
-            max_cache_size: initial_cache_size,
-            encryption_key,
+            namespace: config.namespace.clone(),
+            memory_capacity_bytes: config.memory_capacity_bytes,
+            disk_capacity_bytes: config.disk_capacity_bytes,
+            backup_bytes: AtomicU64::new(0),
+            mmap_min_value_bytes: config.mmap_min_value_bytes,
+            verify_on_load: config.verify_on_load,
+            load_report: None,
+            compression: CompressionSettings {
+                codec: config.compression_codec,
+                min_bytes: config.compression_min_bytes,
+                level: config.compression_level,
+            },
+            encryption,
+            backup_recipients,
+            backup_identity_file: config.backup_identity_file.clone(),
         };
-        disk_cache.load_from_disk().await?; // Load existing cache
+        disk_cache.load_report = disk_cache.load_from_disk().await?; // Load existing cache
+        disk_cache.measure_backups().await.map_err(CacheError::IoError)?;
         Ok(disk_cache)
     }
 
-    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), CacheError> {
-        let mut map = self.map.lock().await;
-        map.insert(
-            key.to_string(),
-            CacheEntry {
-                value: if let Some(encryption_key) = &self.encryption_key {
-                    self.encrypt(value.as_bytes(), encryption_key).await?
-                } else {
-                    value.to_string()
-                },
-                expiry: ttl.map(|d| Instant::now() + d),
-                access_count: 0,
-            },
-        );
-        self.evict_if_necessary().await; // Evict if cache exceeds max size
+    async fn set(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> Result<(), CacheError> {
+        self.audited(AuditAction::Write, Some(key), async {
+            // Compress and encrypt or checksum before touching any lock
+            let stored = check_shared_key(key).and_then(|()| self.encode_value(key, value))?;
+            self.write_entry(key, stored, ttl).await
+        })
+        .await
+    }
+
+    // Takes the value already in stored form.
+    async fn write_entry(&self, key: &str, stored: Vec<u8>, ttl: Option<Duration>) -> Result<(), CacheError> {
+        let entry = CacheEntry {
+            value: Bytes::from(stored),
+            expiry: ttl.map(|d| Utc::now().timestamp_millis() + d.as_millis() as i64),
+            access_count: 0,
+        };
+        {
+            // Held until the resident copy matches what was written
+            let _key = self.map.lock_key(key).await;
+            {
+                let _writer = self.checkpoint_lock.read().await;
+                if let Some(wal) = &self.wal {
+                    wal.append_put(key, &entry.value, entry.expiry).await.map_err(CacheError::IoError)?;
+                }
+                // Only the new record is appended; nothing else on disk is rewritten
+                self.store.put(key, &entry.value, entry.expiry).await.map_err(CacheError::IoError)?;
+            }
+            // New writes start out hot
+            self.map.with_shard(key, |shard| {
+                shard.entries.insert(key.to_string(), entry);
+                shard.policy.on_insert(key);
+            });
+        }
+        self.disk_policy().on_insert(key);
+        self.evict_if_necessary().await?; // Demote or evict if either tier is over budget
         Ok(())
     }
 
-    async fn get(&self, key: &str) -> Result<Option<String>, CacheError> {
-        let mut map = self.map.lock().await;
-        match map.get_mut(key) {
-            Some(entry) => {
-                entry.access_count += 1;
-                if let Some(expiry) = entry.expiry {
-                    if expiry <= Instant::now() {
-                        return Ok(None); // Entry expired
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError> {
+        self.audited(AuditAction::Read, Some(key), async {
+            check_shared_key(key)?;
+            self.read_entry(key, None).await
+        })
+        .await
+    }
+
+    // With `tenant_key` set, only values wrapped by that tenant key are returned.
+    async fn read_entry(&self, key: &str, tenant_key: Option<u32>) -> Result<Option<Vec<u8>>, CacheError> {
+        match self.resident_value(key) {
+            Some(None) => {
+                self.metrics.record_miss();
+                Ok(None) // Entry expired
+            }
+            Some(Some(value)) => {
+                self.metrics.record_memory_hit();
+                self.disk_eviction.record_access(key);
+                check_wrapped_by(&value, tenant_key)?;
+                Ok(Some(self.decode_value(key, &value)?))
+            }
+            None => {
+                // Not resident in memory, fall back to the record on disk
+                let plaintext = {
+                    // Held so that a write or delete racing this read can't be undone by the promote below
+                    let _key = self.map.lock_key(key).await;
+                    let _reader = self.checkpoint_lock.read().await;
+                    let record = match self.store.get(key).await.map_err(CacheError::IoError)? {
+                        Some(record) => record,
+                        None => {
+                            self.metrics.record_miss();
+                            return Ok(None); // Key not found
+                        }
+                    };
+                    let entry = CacheEntry {
+                        value: Bytes::from(record.value),
+                        expiry: record.expiry,
+                        access_count: 1,
+                    };
+                    if entry.is_expired() {
+                        self.metrics.record_miss();
+                        return Ok(None); // Expired while the process was down or since the last sweep
                     }
-                }
-                if let Some(encryption_key) = &self.encryption_key {
-                    Ok(Some(self.decrypt(&entry.value, encryption_key).await?))
-                } else {
-                    Ok(Some(entry.value.clone()))
-                }
+                    check_wrapped_by(&entry.value, tenant_key)?;
+                    let plaintext = self.decode_value(key, &entry.value)?;
+                    self.metrics.record_disk_hit();
+                    self.disk_eviction.record_access(key);
+                    self.promote(key, entry);
+                    plaintext
+                };
+                self.evict_if_necessary().await?;
+                Ok(Some(plaintext))
             }
-            None => Ok(None), // Key not found
         }
     }
 
-    async fn load_from_disk(&self) -> Result<(), CacheError> {
-        let cache_file_path = self.cache_dir.join(CACHE_FILE);
-        let cache_file = match File::open(&cache_file_path).await {
-            Ok(file) => file,
-            Err(_) => return Ok(()), // No cache file found
-        };
-        let mut decoder = GzDecoder::new(cache_file);
-        let mut json_string = String::new();
-        decoder.read_to_string(&mut json_string).await.map_err(CacheError::IoError)?;
+    // Zero-copy variant of `get` for large values. Memory-tier hits share the
+    // resident buffer, and unencrypted values of at least `mmap_min_value_bytes`
+    // in sealed segments are borrowed from a mapping of the segment file. Those
+    // are not promoted: the page cache already keeps them hot and copying them
+    // into the memory tier is exactly what this avoids. Encrypted and compressed
+    // values still have to be decoded into a fresh buffer.
+    async fn get_ref(&self, key: &str) -> Result<Option<ValueGuard>, CacheError> {
+        self.audited(AuditAction::Read, Some(key), async {
+            check_shared_key(key)?;
+            self.read_entry_ref(key).await
+        })
+        .await
+    }
 
-        let cache_map: HashMap<String, CacheEntry> =
-            serde_json::from_str(&json_string).map_err(CacheError::DeserializationError)?;
+    async fn read_entry_ref(&self, key: &str) -> Result<Option<ValueGuard>, CacheError> {
+        if self.encryption.is_some() {
+            return Ok(self.read_entry(key, None).await?.map(|value| ValueGuard::Shared(Bytes::from(value))));
+        }
 
-        let mut map = self.map.lock().await;
-        *map = cache_map;
+        match self.resident_value(key) {
+            Some(None) => {
+                self.metrics.record_miss();
+                return Ok(None); // Entry expired
+            }
+            Some(Some(value)) => {
+                let range = integrity::unframe(key, &value)?;
+                self.metrics.record_memory_hit();
+                self.disk_eviction.record_access(key);
+                return Ok(Some(compression::decompress_guard(ValueGuard::Shared(value.slice(range)))?));
+            }
+            None => {}
+        }
 
-        if let Some(encryption_key) = &self.encryption_key {
-            // Verify integrity of cache entries
-            for entry in map.values() {
-                if let Err(_) = self.decrypt(&entry.value, encryption_key).await {
-                    return Err(CacheError::IntegrityError);
+        let (record, range) = {
+            // As in `read_entry`, so the promote below can't undo a racing write or delete
+            let _key = self.map.lock_key(key).await;
+            let _reader = self.checkpoint_lock.read().await;
+            let record = match self.store.get_ref(key, self.mmap_min_value_bytes).await.map_err(CacheError::IoError)? {
+                Some(record) => record,
+                None => {
+                    self.metrics.record_miss();
+                    return Ok(None); // Key not found
                 }
+            };
+            if record.expiry.is_some_and(|expiry| expiry <= Utc::now().timestamp_millis()) {
+                self.metrics.record_miss();
+                return Ok(None);
+            }
+            let range = integrity::unframe(key, &record.value)?;
+            self.metrics.record_disk_hit();
+            self.disk_eviction.record_access(key);
+
+            if let ValueGuard::Shared(value) = &record.value {
+                let entry = CacheEntry {
+                    value: value.clone(),
+                    expiry: record.expiry,
+                    access_count: 1,
+                };
+                self.promote(key, entry);
+            }
+            (record, range)
+        };
+        self.evict_if_necessary().await?;
+        Ok(Some(compression::decompress_guard(record.value.slice(range))?))
+    }
+
+    // Looks a key up in the memory tier: `Some(None)` if it was resident but
+    // expired, `None` if it is not resident at all. Only a refcounted handle to
+    // the stored bytes leaves the shard lock.
+    fn resident_value(&self, key: &str) -> Option<Option<Bytes>> {
+        self.map.with_shard(key, |shard| match shard.entries.get_mut(key) {
+            Some(entry) if entry.is_expired() => {
+                // Dropped from memory now; the sweeper tombstones it on disk
+                shard.entries.remove(key);
+                shard.policy.on_remove(key);
+                Some(None)
+            }
+            Some(entry) => {
+                entry.access_count += 1;
+                shard.policy.on_access(key);
+                Some(Some(entry.value.clone()))
+            }
+            None => None,
+        })
+    }
+
+    // Copies a disk hit back into the memory tier. Called with the key lock
+    // held, after the record was read under it; a copy that became resident in
+    // the meantime is at least as new and is kept. The caller evicts once the
+    // key lock is released.
+    fn promote(&self, key: &str, entry: CacheEntry) {
+        let promoted = self.map.with_shard(key, |shard| {
+            if shard.entries.get_mut(key).is_some() {
+                return false;
             }
+            shard.entries.insert(key.to_string(), entry);
+            shard.policy.on_insert(key);
+            true
+        });
+        if promoted {
+            self.metrics.record_promotion();
         }
+    }
 
-        Ok(())
+    async fn delete(&self, key: &str) -> Result<(), CacheError> {
+        self.audited(AuditAction::Delete, Some(key), async {
+            check_shared_key(key)?;
+            self.delete_entry(key).await
+        })
+        .await
     }
 
-    async fn save_to_disk(&self) -> Result<(), CacheError> {
-        let cache_file_path = self.cache_dir.join(CACHE_FILE);
-        let cache_map = self.map.lock().await.clone();
-        let json_string = serde_json::to_string(&cache_map).map_err(CacheError::SerializationError)?;
+    // Also used by eviction and expiry, which are not audited.
+    async fn delete_entry(&self, key: &str) -> Result<(), CacheError> {
+        let _key = self.map.lock_key(key).await;
+        self.map.with_shard(key, |shard| {
+            shard.entries.remove(key);
+            shard.policy.on_remove(key);
+        });
+        self.disk_policy().on_remove(key);
+        let _writer = self.checkpoint_lock.read().await;
+        if let Some(wal) = &self.wal {
+            wal.append_delete(key).await.map_err(CacheError::IoError)?;
+        }
+        self.store.delete(key).await.map_err(CacheError::IoError)
+    }
 
-        let cache_file = File::create(&cache_file_path).await.map_err(CacheError::IoError)?;
-        let mut encoder = GzEncoder::new(cache_file, Compression::default());
-        encoder.write_all(json_string.as_bytes()).await.map_err(CacheError::IoError)?;
+    async fn set_str(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), CacheError> {
+        self.set(key, value.as_bytes(), ttl).await
+    }
+
+    async fn get_str(&self, key: &str) -> Result<Option<String>, CacheError> {
+        match self.get(key).await? {
+            Some(bytes) => String::from_utf8(bytes).map(Some).map_err(|_| CacheError::Utf8Error),
+            None => Ok(None),
+        }
+    }
+
+    // Returns what verification found when `verify_on_load` is set.
+    async fn load_from_disk(&self) -> Result<Option<VerificationReport>, CacheError> {
+        // Only the segment index is rebuilt here; values are read lazily on `get`
+        self.store.reload().await.map_err(CacheError::IoError)?;
+        if let Some(wal) = &self.wal {
+            // Re-apply writes acknowledged after the last checkpoint
+            let _checkpoint = self.checkpoint_lock.write().await;
+            for record in wal.replay().await.map_err(CacheError::IoError)? {
+                match record {
+                    WalRecord::Put { key, value, expiry } => self.store.put(&key, &value, expiry).await,
+                    WalRecord::Delete { key } => self.store.delete(&key).await,
+                }
+                .map_err(CacheError::IoError)?;
+            }
+            self.store.flush().await.map_err(CacheError::IoError)?;
+            wal.checkpoint().await.map_err(CacheError::IoError)?;
+        }
+        let report = if self.verify_on_load {
+            Some(self.verify_entries().await?)
+        } else {
+            None
+        };
+        self.reset_eviction().await;
+        Ok(report)
+    }
 
+    async fn save_to_disk(&self) -> Result<(), CacheError> {
+        // Records are already appended by `set`, so saving only has to make them durable
+        let _checkpoint = self.checkpoint_lock.write().await;
+        self.store.flush().await.map_err(CacheError::IoError)?;
+        if let Some(wal) = &self.wal {
+            wal.checkpoint().await.map_err(CacheError::IoError)?;
+        }
+        if let Some(audit) = &self.audit {
+            audit.sync().await.map_err(CacheError::IoError)?;
+        }
         Ok(())
     }
 
+    // A segment snapshot stays sealed under the live keyring, so once backups
+    // go to public keys they are taken as an archive instead.
     async fn backup(&self) -> Result<(), CacheError> {
-        let cache_file_path = self.cache_dir.join(CACHE_FILE);
-        let backup_file_path = self.cache_dir.join(BACKUP_FILE);
-        fs::copy(&cache_file_path, &backup_file_path).map_err(CacheError::IoError)?;
-        Ok(())
+        if self.uses_backup_archive() {
+            self.backup_to_disk().await?;
+        } else {
+            let backup_dir = self.cache_dir.join(BACKUP_DIR);
+            self.audited(AuditAction::Backup, Some(BACKUP_DIR), async {
+                self.store.snapshot_to(&backup_dir).await.map_err(CacheError::IoError)
+            })
+            .await?;
+        }
+        self.measure_backups().await.map_err(CacheError::IoError)
     }
 
     async fn restore_backup(&self) -> Result<(), CacheError> {
-        let cache_file_path = self.cache_dir.join(CACHE_FILE);
-        let backup_file_path = self.cache_dir.join(BACKUP_FILE);
-        fs::copy(&backup_file_path, &cache_file_path).map_err(CacheError::IoError)?;
+        if self.uses_backup_archive() {
+            return self.restore_from_backup().await;
+        }
+        self.audited(AuditAction::Restore, Some(BACKUP_DIR), self.restore_snapshot()).await
+    }
+
+    async fn restore_snapshot(&self) -> Result<(), CacheError> {
+        let backup_dir = self.cache_dir.join(BACKUP_DIR);
+        let _checkpoint = self.checkpoint_lock.write().await;
+        self.store.restore_from(&backup_dir).await.map_err(CacheError::IoError)?;
+        if let Some(wal) = &self.wal {
+            // Logged writes belong to the state being replaced
+            wal.checkpoint().await.map_err(CacheError::IoError)?;
+        }
+        self.reset_eviction().await;
         Ok(())
     }
 
     async fn clean_cache(&self) -> Result<(), CacheError> {
-        let cache_file_path = self.cache_dir.join(CACHE_FILE);
-        fs::remove_file(&cache_file_path).or_else(|err| {
-            if err.kind() == io::ErrorKind::NotFound {
-                Ok(())
-            } else {
-                Err(CacheError::CleanupError(format!("Failed to clean cache: {}", err)))
-            }
-        })?;
+        let _checkpoint = self.checkpoint_lock.write().await;
+        if let Some(wal) = &self.wal {
+            wal.checkpoint().await.map_err(CacheError::IoError)?;
+        }
+        self.store
+            .clear()
+            .await
+            .map_err(|err| CacheError::CleanupError(format!("Failed to clean cache: {}", err)))?;
+        self.reset_eviction().await;
         Ok(())
     }
 
-    async fn encrypt(&self, data: &[u8], key: &[u8]) -> Result<String, CacheError> {
-        let cipher = Aes256Gcm::new(&key.into());
-        let nonce = aes_gcm::Nonce::generate();
-        let ciphertext = cipher.encrypt(&nonce, data).map_err(|_| CacheError::EncryptionError)?;
-        Ok(base64::encode(&nonce.to_bytes()) + &base64::encode(&ciphertext))
-    }
-
-    async fn decrypt(&self, data: &str, key: &[u8]) -> Result<String, CacheError> {
-        let cipher = Aes256Gcm::new(&key.into());
-        let nonce_length = aes_gcm::Nonce::SIZE;
-        let nonce_data = base64::decode(&data[..nonce_length]).map_err(|_| CacheError::DecryptionError)?;
-        let nonce = aes_gcm::Nonce::from_slice(&nonce_data);
-        let ciphertext = base64::decode(&data[nonce_length..]).map_err(|_| CacheError::DecryptionError)?;
-        let plaintext = cipher.decrypt(nonce.unwrap(), &ciphertext).map_err(|_| CacheError::DecryptionError)?;
-        String::from_utf8(plaintext).map_err(|_| CacheError::DecryptionError)
-    }
-
-    async fn evict_if_necessary(&self) {
-        // Acquire a non-blocking lock to check the cache size and perform eviction if necessary
-        if let Some(mut guard) = self.write_semaphore.try_acquire_owned() {
-            let mut map = self.map.lock().await;
-            if map.len() > self.max_cache_size {
-                // Count accesses for each entry
-                let mut access_counts: HashMap<&String, usize> = HashMap::new();
-                for (key, entry) in map.iter() {
-                    access_counts.insert(key, entry.access_count);
-                }
-                // Sort entries by access count, breaking ties by expiry time
-                let mut entries: Vec<(&String, &CacheEntry)> = map.iter().collect();
-                entries.sort_by(|a, b| {
-                    let count_cmp = access_counts.get(b.0).unwrap().cmp(access_counts.get(a.0).unwrap());
-                    if count_cmp == Ordering::Equal {
-                        let expiry_a = a.1.expiry.unwrap_or(Instant::now());
-                        let expiry_b = b.1.expiry.unwrap_or(Instant::now());
-                        expiry_b.cmp(&expiry_a)
-                    } else {
-                        count_cmp
-                    }
-                });
-                // Remove the least frequently accessed entries until cache size is within limits
-                for (key, _) in entries.into_iter().take(map.len() - self.max_cache_size) {
-                    map.remove(&key);
+    // Empties the memory tier and restarts the disk tier's policy tracking exactly the keys on disk.
+    async fn reset_eviction(&self) {
+        let sizing_hint = policy_sizing_hint(self.memory_capacity_bytes, self.map.shard_count());
+        self.map.reset(|| new_policy(self.eviction_policy, sizing_hint));
+
+        let mut policy = new_policy(self.eviction_policy, policy_sizing_hint(self.disk_capacity_bytes, 1));
+        for key in self.store.keys().await {
+            policy.on_insert(&key);
+        }
+        self.disk_eviction.replace(policy);
+    }
+
+    fn disk_policy(&self) -> MutexGuard<'_, Box<dyn EvictionPolicy>> {
+        self.disk_eviction.lock()
+    }
+
+    // The stored form of a value: an encrypted envelope, or a checksummed frame.
+    fn encode_value(&self, key: &str, value: &[u8]) -> Result<Vec<u8>, CacheError> {
+        let value = compression::compress(value, &self.compression)?;
+        match &self.encryption {
+            Some(encryption) => Ok(encryption.encrypt(&value, self.entry_context(key))?),
+            None => Ok(integrity::frame(key, &value)),
+        }
+    }
+
+    fn decode_value(&self, key: &str, stored: &[u8]) -> Result<Vec<u8>, CacheError> {
+        match &self.encryption {
+            Some(encryption) => {
+                let value = encryption.decrypt(stored, self.entry_context(key))?;
+                if compression::is_compressed(&value) {
+                    Ok(compression::decompress(&value)?.into_owned())
+                } else {
+                    Ok(value)
                 }
             }
-        } else {
-            // Failed to acquire the semaphore, indicating high contention
-            // Increase the maximum cache size or implement a more efficient eviction strategy
-            println!("Failed to acquire write semaphore for eviction, consider increasing cache size or optimizing eviction strategy.");
+            None => Ok(compression::decompress(&stored[integrity::unframe(key, stored)?])?.into_owned()),
         }
     }
 
-    async fn load_or_generate_encryption_key() -> Result<Vec<u8>, CacheError> {
-        let key_file_path = Path::new(ENCRYPTION_KEY_FILE);
-        if key_file_path.exists() {
-            fs::read(key_file_path).map_err(|e| CacheError::IoError(e))
-        } else {
-            let mut key = vec![0u8; 32]; // 256-bit key
-            OsRng.fill_bytes(&mut key); // Fill key with random bytes
-            fs::write(key_file_path, &key).map_err(|e| CacheError::IoError(e))?;
-            Ok(key)
+    fn entry_context<'a>(&'a self, key: &'a str) -> EntryContext<'a> {
+        EntryContext {
+            namespace: &self.namespace,
+            key,
         }
     }
 }
@@ -250,10 +610,197 @@ impl DiskCache {
 struct Config {
     encryption_enabled: bool,
     auto_update_enabled: bool,
+    #[serde(default)]
+    wal_enabled: bool,
+    #[serde(default)]
+    wal_fsync: FsyncPolicy,
+    #[serde(default = "default_expiry_sweep_secs")]
+    expiry_sweep_secs: u64,
+    #[serde(default)]
+    eviction_policy: CacheEvictionPolicy,
+    #[serde(default = "default_memory_capacity_bytes")]
+    memory_capacity_bytes: u64,
+    #[serde(default = "default_disk_capacity_bytes")]
+    disk_capacity_bytes: u64,
+    #[serde(default = "default_map_shards")]
+    map_shards: usize,
+    #[serde(default = "default_mmap_min_value_bytes")]
+    mmap_min_value_bytes: u32,
+    #[serde(default)]
+    io_backend: IoBackendKind,
+    #[serde(default = "default_io_queue_depth")]
+    io_queue_depth: u32,
+    #[serde(default = "default_compaction_interval_secs")]
+    compaction_interval_secs: u64,
+    #[serde(default = "default_compaction_garbage_ratio")]
+    compaction_garbage_ratio: f64, // Share of a sealed segment that must be dead before it is rewritten
+    #[serde(default = "default_compaction_max_bytes_per_sec")]
+    compaction_max_bytes_per_sec: u64, // 0 disables throttling
+    #[serde(default = "default_reencryption_interval_secs")]
+    reencryption_interval_secs: u64,
+    #[serde(default = "default_key_dir")]
+    key_dir: PathBuf, // Keyring, key file and KDF params; created 0700 if missing
+    #[serde(default)]
+    master_key: KeySource,
+    #[serde(default)]
+    master_key_refresh_secs: u64, // 0 fetches the master key once at startup
+    #[serde(default)]
+    encryption_algorithm: Algorithm, // Used for new writes only
+    #[serde(default = "default_namespace")]
+    namespace: String,
+    #[serde(default)]
+    verify_on_load: bool, // Check every entry at startup and quarantine corrupt ones
+    #[serde(default)]
+    compression_codec: Codec, // For new writes; "none" disables compression
+    #[serde(default = "default_compression_min_bytes")]
+    compression_min_bytes: usize, // Smaller values are stored as they are
+    #[serde(default)]
+    compression_level: Option<i32>, // Codec default when unset; lz4 has no levels
+    #[serde(default)]
+    backup_recipients: Vec<String>, // age X25519 public keys; backups are encrypted to these when set
+    #[serde(default)]
+    backup_identity_file: Option<PathBuf>, // Private keys for restoring public-key encrypted backups
+    #[serde(default)]
+    backup_interval_secs: u64, // 0 only backs up on request
+    #[serde(default)]
+    audit_log_enabled: bool,
+    #[serde(default)]
+    audit_actor: Option<String>, // Recorded as who did what; defaults to the user and process ID
+}
+
+fn default_memory_capacity_bytes() -> u64 {
+    DEFAULT_MEMORY_CAPACITY_BYTES
+}
+
+fn default_disk_capacity_bytes() -> u64 {
+    DEFAULT_DISK_CAPACITY_BYTES
+}
+
+fn default_map_shards() -> usize {
+    DEFAULT_MAP_SHARDS
+}
+
+fn default_mmap_min_value_bytes() -> u32 {
+    DEFAULT_MMAP_MIN_VALUE_BYTES
+}
+
+fn default_io_queue_depth() -> u32 {
+    DEFAULT_IO_QUEUE_DEPTH
+}
+
+fn default_compaction_interval_secs() -> u64 {
+    DEFAULT_COMPACTION_INTERVAL_SECS
+}
+
+fn default_compaction_garbage_ratio() -> f64 {
+    DEFAULT_COMPACTION_GARBAGE_RATIO
+}
+
+fn default_compaction_max_bytes_per_sec() -> u64 {
+    DEFAULT_COMPACTION_MAX_BYTES_PER_SEC
+}
+
+fn default_reencryption_interval_secs() -> u64 {
+    DEFAULT_REENCRYPTION_INTERVAL_SECS
+}
+
+fn default_key_dir() -> PathBuf {
+    PathBuf::from(DEFAULT_KEY_DIR)
+}
+
+fn default_namespace() -> String {
+    DEFAULT_NAMESPACE.to_string()
+}
+
+fn default_compression_min_bytes() -> usize {
+    DEFAULT_COMPRESSION_MIN_BYTES
+}
+
+// Each memory shard's policy only ever sees its share of the keys.
+fn policy_sizing_hint(capacity_bytes: u64, shard_count: usize) -> usize {
+    (capacity_bytes / POLICY_SIZING_ENTRY_BYTES) as usize / shard_count.max(1)
+}
+
+fn default_expiry_sweep_secs() -> u64 {
+    DEFAULT_EXPIRY_SWEEP_SECS
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            encryption_enabled: true,
+            auto_update_enabled: true,
+            wal_enabled: true,
+            wal_fsync: FsyncPolicy::default(),
+            expiry_sweep_secs: DEFAULT_EXPIRY_SWEEP_SECS,
+            eviction_policy: CacheEvictionPolicy::default(),
+            memory_capacity_bytes: DEFAULT_MEMORY_CAPACITY_BYTES,
+            disk_capacity_bytes: DEFAULT_DISK_CAPACITY_BYTES,
+            map_shards: DEFAULT_MAP_SHARDS,
+            mmap_min_value_bytes: DEFAULT_MMAP_MIN_VALUE_BYTES,
+            io_backend: IoBackendKind::default(),
+            io_queue_depth: DEFAULT_IO_QUEUE_DEPTH,
+            compaction_interval_secs: DEFAULT_COMPACTION_INTERVAL_SECS,
+            compaction_garbage_ratio: DEFAULT_COMPACTION_GARBAGE_RATIO,
+            compaction_max_bytes_per_sec: DEFAULT_COMPACTION_MAX_BYTES_PER_SEC,
+            reencryption_interval_secs: DEFAULT_REENCRYPTION_INTERVAL_SECS,
+            key_dir: PathBuf::from(DEFAULT_KEY_DIR),
+            master_key: KeySource::default(),
+            master_key_refresh_secs: 0,
+            encryption_algorithm: Algorithm::default(),
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            verify_on_load: false,
+            compression_codec: Codec::default(),
+            compression_min_bytes: DEFAULT_COMPRESSION_MIN_BYTES,
+            compression_level: None,
+            backup_recipients: Vec::new(),
+            backup_identity_file: None,
+            backup_interval_secs: 0,
+            audit_log_enabled: false,
+            audit_actor: None,
+        }
+    }
+}
+
+// Parses an environment variable the way the same setting is read from config.json.
+fn env_setting<T: DeserializeOwned>(name: &str) -> Result<Option<T>, CacheError> {
+    let value = match env::var(name) {
+        Ok(value) => value,
+        Err(_) => return Ok(None),
+    };
+    let value = value.trim();
+    serde_json::from_str(value)
+        .or_else(|_| serde_json::from_value(serde_json::Value::String(value.to_string())))
+        .map(Some)
+        .map_err(CacheError::DeserializationError)
 }
 
 impl Config {
     async fn load() -> Result<Self, CacheError> {
+        let mut config = Self::load_file()?;
+        config.apply_env_overrides()?;
+        Ok(config)
+    }
+
+    // Settings listed in .env.example take precedence over config.json.
+    fn apply_env_overrides(&mut self) -> Result<(), CacheError> {
+        if let Some(bytes) = env_setting("CACHE_MEMORY_CAPACITY_BYTES")? {
+            self.memory_capacity_bytes = bytes;
+        }
+        if let Some(bytes) = env_setting("CACHE_DISK_CAPACITY_BYTES")? {
+            self.disk_capacity_bytes = bytes;
+        }
+        if let Some(codec) = env_setting("COMPRESSION_CODEC")? {
+            self.compression_codec = codec;
+        }
+        if let Some(bytes) = env_setting("COMPRESSION_MIN_BYTES")? {
+            self.compression_min_bytes = bytes;
+        }
+        self.master_key = self.master_key.clone().with_env_override()?;
+        Ok(())
+    }
+
+    fn load_file() -> Result<Self, CacheError> {
         let config_file_path = Path::new(CONFIG_FILE);
         if config_file_path.exists() {
             let config_str = fs::read_to_string(config_file_path)
@@ -261,49 +808,205 @@ impl Config {
         serde_json::from_str(&config_str)
             .map_err(CacheError::DeserializationError)
     } else {
-        let default_config = Config {
-            encryption_enabled: true,
-            auto_update_enabled: true,
-        };
+        let default_config = Config::default();
         let default_config_str = serde_json::to_string(&default_config)
             .map_err(CacheError::SerializationError)?;
-        fs::write(config_file_path, default_config_str)
+        atomic_write::write(config_file_path, default_config_str.as_bytes())
             .map_err(CacheError::IoError)?;
         Ok(default_config)
     }
 }
 
+// The binary never writes config.json; this is for code embedding the cache.
+#[allow(dead_code)]
 async fn save(&self) -> Result<(), CacheError> {
     let config_file_path = Path::new(CONFIG_FILE);
     let config_str = serde_json::to_string(self)
         .map_err(CacheError::SerializationError)?;
-    fs::write(config_file_path, config_str)
+    atomic_write::write(config_file_path, config_str.as_bytes())
         .map_err(CacheError::IoError)?;
     Ok(())
 }
 }
 
+// Administrative commands, run as `trust <command> [args]`.
+async fn run_command(command: &str, args: &[String]) -> Result<(), CacheError> {
+    match command {
+        "verify-audit-log" => {
+            let dir = args.first().map_or_else(|| PathBuf::from(CACHE_DIR), PathBuf::from);
+            let report = audit_log::verify(&dir).await.map_err(CacheError::IoError)?;
+            println!("{}", report);
+            if report.is_intact() {
+                Ok(())
+            } else {
+                Err(CacheError::IntegrityError)
+            }
+        }
+        "get" => {
+            let key = args.first().unwrap_or_else(|| usage(command, "<key>"));
+            let cache = open_cache().await?;
+            // Large values come straight from the segment mapping
+            match cache.get_ref(key).await? {
+                Some(value) => io::stdout().write_all(&value).map_err(CacheError::IoError),
+                None => Err(CacheError::NotFound),
+            }
+        }
+        "delete" => {
+            let key = args.first().unwrap_or_else(|| usage(command, "<key>"));
+            open_cache().await?.delete(key).await
+        }
+        "quarantine" => {
+            let cache = open_cache().await?;
+            if let Some(path) = cache.set_aside_quarantine_report() {
+                println!("An unreadable report from an earlier run was moved to {}", path.display());
+            }
+            for entry in cache.quarantined_entries() {
+                let file = entry.file.as_deref().unwrap_or("bytes unreadable");
+                println!("{}  {}  {} ({})", entry.quarantined_at, entry.key, entry.reason, file);
+            }
+            Ok(())
+        }
+        "rotate-key" => {
+            let key_id = open_cache().await?.rotate_encryption_key().await?;
+            println!("Key {} is now current; run reencrypt to move existing entries onto it.", key_id);
+            Ok(())
+        }
+        "reencrypt" => {
+            let report = open_cache().await?.reencrypt().await?;
+            for (key, error) in &report.failed {
+                println!("Could not re-encrypt '{}': {}", key, error);
+            }
+            println!("Re-encrypted {} entries, {} failed.", report.migrated, report.failed.len());
+            Ok(())
+        }
+        "retire-key" => {
+            let key_id = match args.first().map(|key_id| key_id.parse::<u32>()) {
+                Some(Ok(key_id)) => key_id,
+                _ => usage(command, "<key_id>"),
+            };
+            open_cache().await?.retire_encryption_key(key_id).await?;
+            println!("Key {} destroyed.", key_id);
+            Ok(())
+        }
+        "tenant-set" => match args {
+            [tenant, key, value] => open_cache().await?.set_for_tenant(tenant, key, value.as_bytes(), None).await,
+            _ => usage(command, "<tenant> <key> <value>"),
+        },
+        "tenant-get" => match args {
+            [tenant, key] => match open_cache().await?.get_for_tenant(tenant, key).await? {
+                Some(value) => io::stdout().write_all(&value).map_err(CacheError::IoError),
+                None => Err(CacheError::NotFound),
+            },
+            _ => usage(command, "<tenant> <key>"),
+        },
+        "tenant-delete" => match args {
+            [tenant, key] => open_cache().await?.delete_for_tenant(tenant, key).await,
+            _ => usage(command, "<tenant> <key>"),
+        },
+        "shred-tenant" | "verify-shred" => {
+            let tenant = args.first().unwrap_or_else(|| usage(command, "<tenant>"));
+            let cache = open_cache().await?;
+            if command == "shred-tenant" {
+                let report = cache.shred_tenant(tenant).await?;
+                println!(
+                    "Destroyed key {:#x} of tenant '{}' and deleted {} entries.",
+                    report.key_id, report.tenant, report.entries_deleted
+                );
+            }
+            let verification = cache.verify_tenant_shredded(tenant).await?;
+            let backup = match (verification.backup_values, &verification.backup_error) {
+                (Some(count), _) => count.to_string(),
+                (None, Some(reason)) => format!("unchecked ({})", reason),
+                (None, None) => "unchecked".to_string(),
+            };
+            println!(
+                "Tenant '{}': key destroyed: {}, entries left: {}, backed-up values: {}, still readable: {}.",
+                verification.tenant,
+                verification.key_destroyed,
+                verification.entries_remaining,
+                backup,
+                verification.readable
+            );
+            if verification.is_erased() {
+                println!("Tenant data is unrecoverable.");
+                Ok(())
+            } else if verification.is_inconclusive() {
+                println!("Inconclusive: no readable data was found, but the backup archive could not be checked.");
+                Err(CacheError::IntegrityError)
+            } else {
+                println!("Tenant data is NOT erased.");
+                Err(CacheError::IntegrityError)
+            }
+        }
+        _ => {
+            println!("Unknown command '{}'. Commands:", command);
+            println!("  get <key>                      Write a value to stdout");
+            println!("  delete <key>                   Delete an entry");
+            println!("  verify-audit-log [cache_dir]   Check the audit log's hash chain and head");
+            println!("  quarantine                     List entries dropped by integrity checks");
+            println!("  rotate-key                     Make a fresh key current for new writes");
+            println!("  reencrypt                      Move every entry onto the current key");
+            println!("  retire-key <key_id>            Destroy a key nothing depends on any more");
+            println!("  tenant-set <tenant> <key> <value>  Store a value under the tenant's key");
+            println!("  tenant-get <tenant> <key>      Write a tenant's value to stdout");
+            println!("  tenant-delete <tenant> <key>   Delete a tenant's entry");
+            println!("  shred-tenant <tenant>          Destroy a tenant's key and delete its entries");
+            println!("  verify-shred <tenant>          Check that a tenant's data can no longer be read");
+            std::process::exit(2);
+        }
+    }
+}
+
+async fn open_cache() -> Result<DiskCache, CacheError> {
+    let config = Config::load().await?;
+    DiskCache::new(CACHE_DIR, &config).await
+}
+
+fn usage(command: &str, arguments: &str) -> ! {
+    println!("Usage: trust {} {}", command, arguments);
+    std::process::exit(2);
+}
+
 #[tokio::main]
 async fn main() -> Result<(), CacheError> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some(command) = args.first() {
+        return run_command(command, &args[1..]).await;
+    }
+
+    let config = Config::load().await?;
+
     println!("Cleaning cache...");
-    let cache = DiskCache::new(CACHE_DIR, 100, true).await?;
+    // Opened once; the periodic tasks below share it
+    let cache = Arc::new(DiskCache::new(CACHE_DIR, &config).await?);
+    if let Some(report) = cache.load_report.as_ref().filter(|report| !report.dropped.is_empty()) {
+        println!("Quarantined {} of {} entries", report.dropped.len(), report.checked);
+    }
     cache.clean_cache().await?;
     println!("Cache cleaned.");
 
     println!("Building cache...");
-    let config = Config::load().await?;
-    let cache = DiskCache::new(CACHE_DIR, 100, config.encryption_enabled).await?;
-    cache.load_from_disk().await?;
+    tokio::spawn(periodic_cleanup(cache.clone(), Duration::from_secs(config.expiry_sweep_secs)));
+    tokio::spawn(periodic_compaction(cache.clone(), Duration::from_secs(config.compaction_interval_secs)));
+    if config.encryption_enabled {
+        tokio::spawn(periodic_reencryption(cache.clone(), Duration::from_secs(config.reencryption_interval_secs)));
+        if config.master_key_refresh_secs > 0 {
+            tokio::spawn(periodic_master_key_refresh(cache.clone(), Duration::from_secs(config.master_key_refresh_secs)));
+        }
+    }
+    if config.backup_interval_secs > 0 {
+        tokio::spawn(periodic_backup(cache.clone(), Duration::from_secs(config.backup_interval_secs)));
+    }
     println!("Cache built.");
 
     let key = "test_key";
     let value = "test_value";
     println!("Setting key-value pair...");
-    cache.set(key, value, Some(Duration::minutes(5))).await?;
+    cache.set_str(key, value, Some(Duration::from_secs(5 * 60))).await?;
     println!("Key-value pair set successfully.");
 
     println!("Retrieving value for key...");
-    if let Some(val) = cache.get(key).await? {
+    if let Some(val) = cache.get_str(key).await? {
         println!("Retrieved value from cache: {}", val);
     } else {
         println!("Value not found in cache");
@@ -321,5 +1024,41 @@ async fn main() -> Result<(), CacheError> {
     cache.restore_backup().await?;
     println!("Cache restored from backup.");
 
+    let stats = cache.stats().await;
+    println!(
+        "Memory tier: {}/{} bytes, {} hits. Disk tier ({}): {}/{} bytes, {} hits. Hit ratio {:.2}.",
+        stats.memory.used_bytes,
+        stats.memory.capacity_bytes,
+        stats.memory.hits,
+        stats.io_backend,
+        stats.disk.used_bytes,
+        stats.disk.capacity_bytes,
+        stats.disk.hits,
+        stats.hit_ratio(),
+    );
+    let compaction = cache.compaction_report().await;
+    println!(
+        "Compaction: {} segments rewritten, {} bytes reclaimed, {} dead bytes pending.",
+        compaction.segments_compacted, compaction.bytes_reclaimed, compaction.dead_bytes,
+    );
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A cache with its keys in its own temporary directory; remove
+    // `cache.cache_dir` when done.
+    pub(crate) async fn test_cache(name: &str, configure: impl FnOnce(&mut Config)) -> DiskCache {
+        let dir = env::temp_dir().join(format!("trust-cache-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        let mut config = Config {
+            key_dir: dir.join("keys"),
+            ..Config::default()
+        };
+        configure(&mut config);
+        DiskCache::new(dir.to_str().unwrap(), &config).await.unwrap()
+    }
+}
@@ -0,0 +1,131 @@
+// Crash-safe file replacement shared by every persistence path.
+//
+// Data is written to a temporary file next to the target, fsynced, renamed over
+// the target and then the directory itself is fsynced so the rename survives a
+// power cut. Readers therefore see either the complete old file or the complete
+// new one, never a truncated mix. Temporary files live in the same directory so
+// the rename never crosses a filesystem. Secrets are written with owner-only
+// permissions from the moment the temporary file exists, and the caller's copy
+// of the bytes is wiped afterwards.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use zeroize::Zeroize;
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn write(path: &Path, data: &[u8]) -> io::Result<()> {
+    write_with(path, false, |file| file.write_all(data))
+}
+
+// Like `write`, but the file is only ever readable by its owner (0600 on Unix).
+pub(crate) fn write_secret(path: &Path, data: &[u8]) -> io::Result<()> {
+    write_with(path, true, |file| file.write_all(data))
+}
+
+// Like `write`, with the contents produced by `fill` as it goes.
+pub(crate) fn write_from(path: &Path, fill: impl FnOnce(&mut File) -> io::Result<()>) -> io::Result<()> {
+    write_with(path, false, fill)
+}
+
+pub(crate) fn copy(from: &Path, to: &Path) -> io::Result<()> {
+    let mut source = File::open(from)?;
+    write_with(to, false, |file| io::copy(&mut source, file).map(|_| ()))
+}
+
+// Makes renames and newly created files inside `dir` durable.
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        File::open(dir)?.sync_all()
+    }
+    // Directories can't be opened as files elsewhere; NTFS journals renames itself
+    #[cfg(not(unix))]
+    {
+        let _ = dir;
+        Ok(())
+    }
+}
+
+pub(crate) async fn write_async(path: &Path, data: Vec<u8>) -> io::Result<()> {
+    let path = path.to_path_buf();
+    blocking(move || write(&path, &data)).await
+}
+
+pub(crate) async fn write_secret_async(path: &Path, mut data: Vec<u8>) -> io::Result<()> {
+    let path = path.to_path_buf();
+    blocking(move || {
+        let result = write_secret(&path, &data);
+        data.zeroize();
+        result
+    })
+    .await
+}
+
+pub(crate) async fn copy_async(from: &Path, to: &Path) -> io::Result<()> {
+    let (from, to) = (from.to_path_buf(), to.to_path_buf());
+    blocking(move || copy(&from, &to)).await
+}
+
+// Copies the first `len` bytes of a file opened earlier, which stays readable
+// even if its path has since been removed.
+pub(crate) async fn copy_prefix_async(from: File, len: u64, to: &Path) -> io::Result<()> {
+    let to = to.to_path_buf();
+    blocking(move || write_with(&to, false, |file| io::copy(&mut (&from).take(len), file).map(|_| ()))).await
+}
+
+pub(crate) async fn sync_dir_async(dir: &Path) -> io::Result<()> {
+    let dir = dir.to_path_buf();
+    blocking(move || sync_dir(&dir)).await
+}
+
+fn write_with(path: &Path, private: bool, fill: impl FnOnce(&mut File) -> io::Result<()>) -> io::Result<()> {
+    let temp = temp_path(path);
+    let result = (|| {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        if private {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        #[cfg(not(unix))]
+        let _ = private;
+        let mut file = options.open(&temp)?;
+        fill(&mut file)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&temp, path)?;
+        sync_dir(parent_dir(path))
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+// `.<name>.<pid>.<n>.tmp`, unique per process and call.
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let n = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    parent_dir(path).join(format!(".{}.{}.{}.tmp", name, process::id(), n))
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+async fn blocking(f: impl FnOnce() -> io::Result<()> + Send + 'static) -> io::Result<()> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(io::Error::other)?
+}
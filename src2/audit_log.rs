@@ -0,0 +1,548 @@
+// Tamper-evident audit log of cache access and administrative operations.
+//
+// When `audit_log_enabled` is set, every read, write and delete made through
+// the cache API, every backup and restore, every key rotation, retirement and
+// keyring reseal, and every tenant shredding is appended to `audit.log` as one
+// JSON line. Evictions and expiry sweeps are housekeeping and are not
+// recorded. Anything but a read is recorded twice: an intent record before the
+// operation starts and its outcome once it is done. An operation whose intent
+// can't be written never runs, so nothing changes unaudited. An outcome that
+// can't be written doesn't change what the operation reports; it is queued and
+// retried with the next write, and until then the intent stands alone, as it
+// does for an operation interrupted by a crash.
+//
+// Reads change nothing, so they are queued rather than written one by one and
+// join the chain with the next mutation or sync. A crash can lose the reads of
+// the last second, never a mutation.
+//
+// Each record carries the SHA-256 of the record before it, and its own hash
+// covers that plus every field, so editing, reordering or dropping a record
+// breaks the chain from that point on. Cutting records off the end would leave
+// a valid chain, so the sequence number and hash of the last record are also
+// written to `audit.head` whenever the log is synced: after each
+// administrative operation, on `save_to_disk` and once a second otherwise. A
+// log that ends before its head, or disagrees with it, fails verification.
+// Anyone able to rewrite both files consistently can still forge history;
+// copying the head off the host closes that gap.
+//
+// Hashed bytes (integers little endian):
+//   seq: u64 | time_unix_ms: i64 | (len: u32 | bytes) for actor, action,
+//   target (empty when absent) and outcome | prev: [u8; 32]
+
+use std::{
+    env, fmt,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    mem,
+    sync::{Arc, Mutex as StdMutex, Weak},
+    time::Duration,
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+    time,
+};
+
+use crate::{atomic_write, master_key::encode_hex, CacheError, DiskCache};
+
+const AUDIT_LOG_FILE: &str = "audit.log";
+const HEAD_FILE: &str = "audit.head";
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+const GENESIS_HASH: [u8; 32] = [0; 32];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AuditAction {
+    Read,
+    Write,
+    Delete,
+    Backup,
+    Restore,
+    RotateKey,
+    RetireKey,
+    ResealKeyring,
+    ShredTenant,
+}
+
+impl AuditAction {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuditAction::Read => "read",
+            AuditAction::Write => "write",
+            AuditAction::Delete => "delete",
+            AuditAction::Backup => "backup",
+            AuditAction::Restore => "restore",
+            AuditAction::RotateKey => "rotate_key",
+            AuditAction::RetireKey => "retire_key",
+            AuditAction::ResealKeyring => "reseal_keyring",
+            AuditAction::ShredTenant => "shred_tenant",
+        }
+    }
+
+    fn changes_state(self) -> bool {
+        self != AuditAction::Read
+    }
+
+    // Synced to disk before the operation starts and before it is reported done.
+    fn is_administrative(self) -> bool {
+        !matches!(self, AuditAction::Read | AuditAction::Write | AuditAction::Delete)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct AuditRecord {
+    pub(crate) seq: u64, // Starts at 1
+    pub(crate) time: i64, // Unix time in milliseconds
+    pub(crate) actor: String,
+    pub(crate) action: AuditAction,
+    pub(crate) target: Option<String>, // Entry key, backup location or key ID
+    pub(crate) outcome: String, // "started", "ok" or the error
+    pub(crate) prev: String, // Hex
+    pub(crate) hash: String, // Hex
+}
+
+impl AuditRecord {
+    fn digest(&self, prev: &[u8; 32]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.seq.to_le_bytes());
+        hasher.update(self.time.to_le_bytes());
+        for field in [
+            self.actor.as_str(),
+            self.action.as_str(),
+            self.target.as_deref().unwrap_or(""),
+            self.outcome.as_str(),
+        ] {
+            hasher.update((field.len() as u32).to_le_bytes());
+            hasher.update(field.as_bytes());
+        }
+        hasher.update(prev);
+        hasher.finalize().into()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AuditHead {
+    seq: u64,
+    hash: String,
+}
+
+// A record waiting for its place in the chain
+struct PendingRecord {
+    time: i64,
+    action: AuditAction,
+    target: Option<String>,
+    outcome: String,
+}
+
+struct AuditState {
+    file: File,
+    len: u64, // End of the last complete record
+    seq: u64,
+    last_hash: [u8; 32],
+    unsynced: bool,
+}
+
+pub(crate) struct AuditLog {
+    head_path: PathBuf,
+    actor: String,
+    pending: StdMutex<Vec<PendingRecord>>,
+    state: Mutex<AuditState>,
+}
+
+impl AuditLog {
+    // Continues the chain from the last complete record. A record torn by a
+    // crash was never acknowledged and is cut off.
+    pub(crate) async fn open(dir: &Path, actor: Option<String>) -> io::Result<Arc<Self>> {
+        let path = dir.join(AUDIT_LOG_FILE);
+        let contents = match fs::read(&path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let complete = contents.iter().rposition(|&byte| byte == b'\n').map_or(0, |newline| newline + 1);
+        let (seq, last_hash) = match contents[..complete].split(|&byte| byte == b'\n').rev().nth(1) {
+            Some(line) => {
+                let record: AuditRecord = serde_json::from_slice(line).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("last audit record is malformed: {}", e))
+                })?;
+                (record.seq, decode_hash(&record.hash).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "last audit record has a malformed hash")
+                })?)
+            }
+            None => (0, GENESIS_HASH),
+        };
+
+        let file = OpenOptions::new().create(true).append(true).open(&path).await?;
+        if complete < contents.len() {
+            file.set_len(complete as u64).await?;
+        }
+        let log = Arc::new(AuditLog {
+            head_path: dir.join(HEAD_FILE),
+            actor: actor.unwrap_or_else(default_actor),
+            pending: StdMutex::new(Vec::new()),
+            state: Mutex::new(AuditState {
+                file,
+                len: complete as u64,
+                seq,
+                last_hash,
+                unsynced: false,
+            }),
+        });
+        tokio::spawn(periodic_sync(Arc::downgrade(&log), SYNC_INTERVAL));
+        Ok(log)
+    }
+
+    // Queues a record to be written with the next one or the next sync.
+    pub(crate) fn queue(&self, action: AuditAction, target: Option<&str>, outcome: String) {
+        let record = PendingRecord {
+            time: Utc::now().timestamp_millis(),
+            action,
+            target: target.map(str::to_string),
+            outcome,
+        };
+        self.pending.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(record);
+    }
+
+    // Writes the queued records and then this one.
+    pub(crate) async fn record(&self, action: AuditAction, target: Option<&str>, outcome: String) -> io::Result<()> {
+        let mut state = self.state.lock().await;
+        let mut records = self.take_pending();
+        let queued = records.len();
+        records.push(PendingRecord {
+            time: Utc::now().timestamp_millis(),
+            action,
+            target: target.map(str::to_string),
+            outcome,
+        });
+        if let Err(e) = self.append(&mut state, &records).await {
+            records.truncate(queued);
+            self.requeue(records);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn take_pending(&self) -> Vec<PendingRecord> {
+        mem::take(&mut *self.pending.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+
+    // Puts records that couldn't be written back ahead of anything queued since
+    fn requeue(&self, records: Vec<PendingRecord>) {
+        let mut pending = self.pending.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        pending.splice(0..0, records);
+    }
+
+    // Chains the records onto the log in one write. On failure whatever part of
+    // the write landed is cut off again, so the chain ends where it did.
+    async fn append(&self, state: &mut AuditState, records: &[PendingRecord]) -> io::Result<()> {
+        let mut seq = state.seq;
+        let mut last_hash = state.last_hash;
+        let mut lines = Vec::new();
+        for pending in records {
+            let mut record = AuditRecord {
+                seq: seq + 1,
+                time: pending.time,
+                actor: self.actor.clone(),
+                action: pending.action,
+                target: pending.target.clone(),
+                outcome: pending.outcome.clone(),
+                prev: encode_hex(&last_hash),
+                hash: String::new(),
+            };
+            let hash = record.digest(&last_hash);
+            record.hash = encode_hex(&hash);
+            serde_json::to_writer(&mut lines, &record).map_err(io::Error::other)?;
+            lines.push(b'\n');
+            seq = record.seq;
+            last_hash = hash;
+        }
+
+        let written = async {
+            state.file.write_all(&lines).await?;
+            state.file.flush().await
+        }
+        .await;
+        if let Err(e) = written {
+            let _ = state.file.set_len(state.len).await;
+            return Err(e);
+        }
+        state.len += lines.len() as u64;
+        state.seq = seq;
+        state.last_hash = last_hash;
+        state.unsynced = true;
+        Ok(())
+    }
+
+    // Makes every record durable, then moves the head up to the last one.
+    pub(crate) async fn sync(&self) -> io::Result<()> {
+        let mut state = self.state.lock().await;
+        let records = self.take_pending();
+        if !records.is_empty() {
+            if let Err(e) = self.append(&mut state, &records).await {
+                self.requeue(records);
+                return Err(e);
+            }
+        }
+        if !state.unsynced {
+            return Ok(());
+        }
+        state.file.sync_data().await?;
+        let head = AuditHead {
+            seq: state.seq,
+            hash: encode_hex(&state.last_hash),
+        };
+        let head = serde_json::to_vec(&head).map_err(io::Error::other)?;
+        atomic_write::write_async(&self.head_path, head).await?;
+        state.unsynced = false;
+        Ok(())
+    }
+}
+
+fn default_actor() -> String {
+    let user = env::var("USER").or_else(|_| env::var("USERNAME")).unwrap_or_else(|_| "unknown".to_string());
+    format!("{} (pid {})", user, std::process::id())
+}
+
+fn decode_hash(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    for (byte, pair) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+async fn periodic_sync(log: Weak<AuditLog>, interval: Duration) {
+    let mut interval_timer = time::interval(interval);
+    loop {
+        interval_timer.tick().await;
+        let log = match log.upgrade() {
+            Some(log) => log,
+            None => break, // Cache dropped, stop syncing
+        };
+        if let Err(e) = log.sync().await {
+            println!("Failed to sync audit log: {}", e);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct AuditVerification {
+    pub(crate) records: u64,
+    pub(crate) anchored: Option<u64>, // Sequence number in the head, if there is one
+    pub(crate) problems: Vec<String>,
+}
+
+impl AuditVerification {
+    pub(crate) fn is_intact(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for AuditVerification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.anchored {
+            Some(seq) => writeln!(f, "{} records, head at record {}", self.records, seq)?,
+            None => writeln!(f, "{} records, no head; truncation of the tail can't be detected", self.records)?,
+        }
+        if self.is_intact() {
+            write!(f, "Audit log is intact")
+        } else {
+            for problem in &self.problems {
+                writeln!(f, "  {}", problem)?;
+            }
+            write!(f, "Audit log FAILED verification ({} problems)", self.problems.len())
+        }
+    }
+}
+
+// Walks the whole chain in `dir` and checks it against the head. Reports every
+// problem rather than stopping at the first.
+pub(crate) async fn verify(dir: &Path) -> io::Result<AuditVerification> {
+    let mut report = AuditVerification::default();
+    let contents = fs::read(dir.join(AUDIT_LOG_FILE)).await?;
+    let head: Option<AuditHead> = match fs::read(dir.join(HEAD_FILE)).await {
+        Ok(head) => match serde_json::from_slice(&head) {
+            Ok(head) => Some(head),
+            Err(e) => {
+                report.problems.push(format!("head file is malformed: {}", e));
+                None
+            }
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    report.anchored = head.as_ref().map(|head| head.seq);
+
+    let mut prev = GENESIS_HASH;
+    let mut expected_seq = 1;
+    let mut head_seen = false;
+    for (index, line) in contents.split(|&byte| byte == b'\n').enumerate() {
+        let line_number = index + 1;
+        if line.is_empty() {
+            continue;
+        }
+        let record: AuditRecord = match serde_json::from_slice(line) {
+            Ok(record) => record,
+            Err(e) => {
+                report.problems.push(format!("line {}: malformed record: {}", line_number, e));
+                continue;
+            }
+        };
+        report.records += 1;
+
+        if record.seq != expected_seq {
+            report.problems.push(format!(
+                "line {}: sequence {} where {} was expected; records were removed or reordered",
+                line_number, record.seq, expected_seq
+            ));
+        }
+        if decode_hash(&record.prev) != Some(prev) {
+            report.problems.push(format!("line {}: does not chain to the record before it", line_number));
+        }
+        let hash = record.digest(&prev);
+        if decode_hash(&record.hash) != Some(hash) {
+            report.problems.push(format!("line {}: hash does not match its contents; record was modified", line_number));
+        }
+        if let Some(head) = &head {
+            if record.seq == head.seq {
+                head_seen = true;
+                if record.hash != head.hash {
+                    report.problems.push(format!("line {}: differs from the record the head points at", line_number));
+                }
+            }
+        }
+
+        // Carry on from what the record claims, so one bad record is reported once
+        prev = decode_hash(&record.hash).unwrap_or(hash);
+        expected_seq = record.seq + 1;
+    }
+
+    if let Some(head) = &head {
+        if !head_seen && head.seq > 0 {
+            report.problems.push(format!(
+                "log ends at record {} but the head points at record {}; the log was truncated",
+                expected_seq - 1,
+                head.seq
+            ));
+        }
+    }
+    Ok(report)
+}
+
+impl DiskCache {
+    // Runs an operation between its intent and outcome records and hands the
+    // outcome back. Fails the operation if its intent can't be written; an
+    // outcome that can't be written is queued for the next write instead.
+    pub(crate) async fn audited<T>(
+        &self,
+        action: AuditAction,
+        target: Option<&str>,
+        operation: impl Future<Output = Result<T, CacheError>>,
+    ) -> Result<T, CacheError> {
+        let audit = match &self.audit {
+            Some(audit) => audit,
+            None => return operation.await,
+        };
+        if action.changes_state() {
+            audit.record(action, target, "started".to_string()).await.map_err(CacheError::IoError)?;
+            if action.is_administrative() {
+                audit.sync().await.map_err(CacheError::IoError)?;
+            }
+        }
+        let result = operation.await;
+        let outcome = match &result {
+            Ok(_) => "ok".to_string(),
+            Err(e) => format!("failed: {:?}", e),
+        };
+        // Reads are only queued, as are outcomes that couldn't be written now
+        if !action.changes_state() || audit.record(action, target, outcome.clone()).await.is_err() {
+            audit.queue(action, target, outcome);
+        } else if action.is_administrative() {
+            // Left unsynced on failure; the periodic sync tries again
+            let _ = audit.sync().await;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn write_log(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("trust-audit-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir_all(&dir).await.unwrap();
+        let log = AuditLog::open(&dir, Some("tester".to_string())).await.unwrap();
+        log.record(AuditAction::Write, Some("a"), "started".to_string()).await.unwrap();
+        log.record(AuditAction::Write, Some("a"), "ok".to_string()).await.unwrap();
+        log.queue(AuditAction::Read, Some("a"), "ok".to_string());
+        log.record(AuditAction::Delete, Some("a"), "started".to_string()).await.unwrap();
+        log.record(AuditAction::Delete, Some("a"), "ok".to_string()).await.unwrap();
+        log.sync().await.unwrap();
+        dir
+    }
+
+    async fn rewrite_lines(dir: &Path, change: impl FnOnce(&mut Vec<String>)) {
+        let path = dir.join(AUDIT_LOG_FILE);
+        let contents = fs::read_to_string(&path).await.unwrap();
+        let mut lines: Vec<String> = contents.lines().map(str::to_string).collect();
+        change(&mut lines);
+        fs::write(&path, lines.iter().map(|line| format!("{}\n", line)).collect::<String>()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn queued_reads_join_the_chain() {
+        let dir = write_log("intact").await;
+        let report = verify(&dir).await.unwrap();
+        assert!(report.is_intact(), "{}", report);
+        assert_eq!(report.records, 5);
+        assert_eq!(report.anchored, Some(5));
+
+        let contents = fs::read_to_string(dir.join(AUDIT_LOG_FILE)).await.unwrap();
+        let read: AuditRecord = serde_json::from_str(contents.lines().nth(2).unwrap()).unwrap();
+        assert_eq!(read.action, AuditAction::Read);
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn catches_an_edited_record() {
+        let dir = write_log("edited").await;
+        rewrite_lines(&dir, |lines| lines[1] = lines[1].replace("\"outcome\":\"ok\"", "\"outcome\":\"failed\"")).await;
+        let report = verify(&dir).await.unwrap();
+        assert!(report.problems.iter().any(|problem| problem.starts_with("line 2: hash does not match")), "{}", report);
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn catches_reordered_records() {
+        let dir = write_log("reordered").await;
+        rewrite_lines(&dir, |lines| lines.swap(1, 2)).await;
+        let report = verify(&dir).await.unwrap();
+        assert!(!report.is_intact());
+        assert!(report.problems.iter().any(|problem| problem.contains("removed or reordered")), "{}", report);
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn catches_a_truncated_log() {
+        let dir = write_log("truncated").await;
+        rewrite_lines(&dir, |lines| {
+            lines.pop();
+        })
+        .await;
+        let report = verify(&dir).await.unwrap();
+        assert_eq!(report.records, 4);
+        assert!(report.problems.iter().any(|problem| problem.contains("the log was truncated")), "{}", report);
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -1,30 +1,286 @@
-use tokio::time::{self, Duration};
+use std::{io, iter, path::Path, sync::Arc};
 
+use tokio::{
+    sync::mpsc,
+    time::{self, Duration},
+};
+use zeroize::Zeroizing;
+
+use crate::{
+    atomic_write,
+    audit_log::AuditAction,
+    backup_encryption::{decrypt_with, encrypt_records_to, is_public_key_encrypted},
+    encryption_service::EntryContext,
+    tenants::is_tenant_value,
+    CacheError, DiskCache,
+};
+
+pub(crate) const BACKUP_ARCHIVE_FILE: &str = "cache_backup.bin";
+// Archives are bound to the cache namespace they were taken from
+const BACKUP_NAMESPACE: &str = "backup";
+const RECORD_HEADER_LEN: usize = 16;
+// Set in `key_len` when the value is in stored form inside a plaintext archive
+const STORED_FORM_FLAG: u32 = 1 << 31;
+// Records queued between the store reads and the blocking archive writer
+const ARCHIVE_QUEUE_RECORDS: usize = 64;
+
+enum Streamed {
+    Record(Zeroizing<Vec<u8>>),
+    Done, // Anything else ending the stream leaves the previous archive in place
+}
+
+pub(crate) struct ArchiveRecord {
+    pub(crate) key: String,
+    pub(crate) value: Vec<u8>,
+    pub(crate) expiry: Option<i64>,
+    pub(crate) stored_form: bool, // Always true unless the archive is encrypted to public keys
+}
+
+// Archive layout: a sequence of
+// `expiry_unix_ms: i64 | key_len: u32 | value_len: u32 | key | value` records
+// holding the stored bytes. When a key is configured the whole archive is
+// encrypted into a single ciphertext envelope (see envelope.rs).
+//
+// With backup recipients configured the records hold plaintext values instead
+// and the archive is encrypted to the recipients' public keys (see
+// backup_encryption.rs), so it does not depend on the live keyring at all.
+// Restoring such an archive stores each value afresh under the live settings.
+// Tenant values are the exception: they stay wrapped by their tenant key and
+// are flagged as such, so shredding a tenant reaches its backups as well.
+// Those archives are streamed into the age writer one record at a time.
+//
+// Keys must stay below `STORED_FORM_FLAG` and values must fit their u32
+// length; an entry that doesn't fails the backup.
 impl DiskCache {
-    async fn backup_to_disk(&self) {
-        let backup_path = self.config.cache_dir.join("cache_backup.gz");
-        let map = self.map.lock().await;
-        let serialized_map = serde_json::to_vec(&*map).expect("Failed to serialize cache map");
-        let encrypted_backup = self.encryption_service.encrypt(&serialized_map);
+    // Whether backups go through the archive rather than the segment snapshot,
+    // which is the case once there are recipients to encrypt it to. An identity
+    // file alone only lets archives be read.
+    pub(crate) fn uses_backup_archive(&self) -> bool {
+        !self.backup_recipients.is_empty()
+    }
+
+    pub(crate) async fn backup_to_disk(&self) -> Result<(), CacheError> {
+        self.audited(AuditAction::Backup, Some(BACKUP_ARCHIVE_FILE), self.write_backup_archive()).await
+    }
+
+    pub(crate) async fn restore_from_backup(&self) -> Result<(), CacheError> {
+        self.audited(AuditAction::Restore, Some(BACKUP_ARCHIVE_FILE), self.restore_backup_archive()).await
+    }
+
+    async fn write_backup_archive(&self) -> Result<(), CacheError> {
+        let backup_path = self.cache_dir.join(BACKUP_ARCHIVE_FILE);
+        if !self.backup_recipients.is_empty() {
+            return self.write_public_key_archive(&backup_path).await;
+        }
+        // Stored-form values only, so nothing in here is plaintext the cache would encrypt
+        let mut archive = Vec::new();
+        for key in self.store.keys().await {
+            if let Some(record) = self.store.get(&key).await.map_err(CacheError::IoError)? {
+                archive.extend_from_slice(&archive_record(&key, &record.value, record.expiry, 0)?);
+            }
+        }
+
+        let archive = match &self.encryption {
+            Some(encryption) => encryption.encrypt(&archive, self.backup_context())?,
+            None => archive,
+        };
+        atomic_write::write_async(&backup_path, archive).await.map_err(CacheError::IoError)
+    }
+
+    // Decoded values are handed to a blocking writer that encrypts them to the
+    // recipients straight into the archive file.
+    async fn write_public_key_archive(&self, backup_path: &Path) -> Result<(), CacheError> {
+        let (sender, mut receiver) = mpsc::channel(ARCHIVE_QUEUE_RECORDS);
+        let (path, recipients) = (backup_path.to_path_buf(), self.backup_recipients.clone());
+        let writer = tokio::task::spawn_blocking(move || {
+            atomic_write::write_from(&path, |file| {
+                let mut done = false;
+                let records = iter::from_fn(|| match receiver.blocking_recv() {
+                    Some(Streamed::Record(record)) => Some(record),
+                    Some(Streamed::Done) => {
+                        done = true;
+                        None
+                    }
+                    None => None,
+                });
+                encrypt_records_to(&recipients, file, records)?;
+                if !done {
+                    return Err(io::Error::new(io::ErrorKind::Interrupted, "backup stopped before its last record"));
+                }
+                Ok(())
+            })
+        });
+
+        let produced: Result<(), CacheError> = async {
+            for key in self.store.keys().await {
+                if let Some(record) = self.store.get(&key).await.map_err(CacheError::IoError)? {
+                    let chunk = if is_tenant_value(&record.value) {
+                        archive_record(&key, &record.value, record.expiry, STORED_FORM_FLAG)?
+                    } else {
+                        let value = Zeroizing::new(self.decode_value(&key, &record.value)?);
+                        archive_record(&key, &value, record.expiry, 0)?
+                    };
+                    if sender.send(Streamed::Record(chunk)).await.is_err() {
+                        break; // The writer failed; its error is returned below
+                    }
+                }
+            }
+            let _ = sender.send(Streamed::Done).await;
+            Ok(())
+        }
+        .await;
+        drop(sender);
 
-        tokio::fs::write(backup_path, encrypted_backup).await.expect("Failed to write backup");
+        let written = writer.await.map_err(|e| CacheError::IoError(io::Error::other(e)))?;
+        produced?;
+        written.map_err(CacheError::IoError)
     }
 
-    async fn restore_from_backup(&self) {
-        let backup_path = self.config.cache_dir.join("cache_backup.gz");
-        let encrypted_backup = tokio::fs::read(backup_path).await.expect("Failed to read backup");
-        let serialized_map = self.encryption_service.decrypt(&encrypted_backup);
-        let map: HashMap<String, CacheEntry> = serde_json::from_slice(&serialized_map).expect("Failed to deserialize cache map");
+    async fn restore_backup_archive(&self) -> Result<(), CacheError> {
+        let mut records = self.read_backup_archive().await?;
+        for record in &mut records {
+            if !record.stored_form {
+                record.value = self.encode_value(&record.key, &record.value)?;
+            }
+        }
 
-        let mut current_map = self.map.lock().await;
-        *current_map = map;
+        let _checkpoint = self.checkpoint_lock.write().await;
+        if let Some(wal) = &self.wal {
+            wal.checkpoint().await.map_err(CacheError::IoError)?;
+        }
+        self.store.clear().await.map_err(CacheError::IoError)?;
+        for record in records {
+            self.store.put(&record.key, &record.value, record.expiry).await.map_err(CacheError::IoError)?;
+        }
+        self.store.flush().await.map_err(CacheError::IoError)?;
+        self.reset_eviction().await;
+        Ok(())
     }
+
+    // Reads and decrypts the archive and splits it into records.
+    pub(crate) async fn read_backup_archive(&self) -> Result<Vec<ArchiveRecord>, CacheError> {
+        let backup_path = self.cache_dir.join(BACKUP_ARCHIVE_FILE);
+        let archive = tokio::fs::read(backup_path).await.map_err(CacheError::IoError)?;
+        let public_key = is_public_key_encrypted(&archive);
+        let archive = match &self.encryption {
+            _ if public_key => {
+                let identity_file = self.backup_identity_file.as_ref().ok_or_else(|| {
+                    CacheError::KeyError("backup is encrypted to a public key; set backup_identity_file".to_string())
+                })?;
+                decrypt_with(identity_file, &archive).await?
+            }
+            Some(encryption) => encryption.decrypt(&archive, self.backup_context())?,
+            None => archive,
+        };
+
+        let mut records = Vec::new();
+        let mut cursor = 0usize;
+        while cursor < archive.len() {
+            let header = archive.get(cursor..cursor + RECORD_HEADER_LEN).ok_or(CacheError::IntegrityError)?;
+            let expiry = match i64::from_le_bytes(header[0..8].try_into().unwrap()) {
+                0 => None,
+                expiry => Some(expiry),
+            };
+            let key_len = u32::from_le_bytes(header[8..12].try_into().unwrap());
+            let stored_form = !public_key || key_len & STORED_FORM_FLAG != 0;
+            let key_len = (key_len & !STORED_FORM_FLAG) as usize;
+            let value_len = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+            cursor += RECORD_HEADER_LEN;
+            let key = archive.get(cursor..cursor + key_len).ok_or(CacheError::IntegrityError)?;
+            let key = String::from_utf8(key.to_vec()).map_err(|_| CacheError::IntegrityError)?;
+            cursor += key_len;
+            let value = archive.get(cursor..cursor + value_len).ok_or(CacheError::IntegrityError)?;
+            cursor += value_len;
+            records.push(ArchiveRecord {
+                key,
+                value: value.to_vec(),
+                expiry,
+                stored_form,
+            });
+        }
+        Ok(records)
+    }
+
+    fn backup_context(&self) -> EntryContext<'_> {
+        EntryContext {
+            namespace: BACKUP_NAMESPACE,
+            key: &self.namespace,
+        }
+    }
+}
+
+fn archive_record(key: &str, value: &[u8], expiry: Option<i64>, flag: u32) -> Result<Zeroizing<Vec<u8>>, CacheError> {
+    if key.len() >= STORED_FORM_FLAG as usize || u32::try_from(value.len()).is_err() {
+        return Err(CacheError::IoError(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("entry '{}' is too large for a backup archive", key),
+        )));
+    }
+    let mut record = Zeroizing::new(Vec::with_capacity(RECORD_HEADER_LEN + key.len() + value.len()));
+    record.extend_from_slice(&expiry.unwrap_or(0).to_le_bytes());
+    record.extend_from_slice(&(key.len() as u32 | flag).to_le_bytes());
+    record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    record.extend_from_slice(key.as_bytes());
+    record.extend_from_slice(value);
+    Ok(record)
 }
 
-async fn periodic_backup(storage: Arc<DiskCache>, interval: Duration) {
+pub(crate) async fn periodic_backup(storage: Arc<DiskCache>, interval: Duration) {
     let mut interval_timer = time::interval(interval);
     loop {
         interval_timer.tick().await;
-        storage.backup_to_disk().await;
+        if let Err(e) = storage.backup().await {
+            println!("Periodic backup failed: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::{secrecy::ExposeSecret, x25519::Identity};
+
+    #[tokio::test]
+    async fn public_key_archives_round_trip() {
+        let identity = Identity::generate();
+        let identity_file = std::env::temp_dir().join(format!("trust-backup-{}-identity", std::process::id()));
+        atomic_write::write_secret(&identity_file, identity.to_string().expose_secret().as_bytes()).unwrap();
+        let recipient = identity.to_public().to_string();
+        let cache = crate::tests::test_cache("backup-archive", |config| {
+            config.backup_recipients = vec![recipient];
+            config.backup_identity_file = Some(identity_file.clone());
+        })
+        .await;
+        cache.set("shared", b"value", None).await.unwrap();
+        cache.set_for_tenant("acme", "key", b"tenant value", None).await.unwrap();
+
+        cache.backup().await.unwrap();
+        let archive = std::fs::read(cache.cache_dir.join(BACKUP_ARCHIVE_FILE)).unwrap();
+        assert!(is_public_key_encrypted(&archive));
+        let records = cache.read_backup_archive().await.unwrap();
+        let shared = records.iter().find(|record| record.key == "shared").unwrap();
+        assert_eq!((shared.value.as_slice(), shared.stored_form), (&b"value"[..], false));
+        assert!(records.iter().find(|record| record.key != "shared").unwrap().stored_form);
+
+        cache.delete("shared").await.unwrap();
+        cache.restore_backup().await.unwrap();
+        assert_eq!(cache.get("shared").await.unwrap().as_deref(), Some(&b"value"[..]));
+        assert_eq!(cache.get_for_tenant("acme", "key").await.unwrap().as_deref(), Some(&b"tenant value"[..]));
+        std::fs::remove_dir_all(&cache.cache_dir).unwrap();
+        std::fs::remove_file(&identity_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn an_identity_alone_keeps_segment_snapshots() {
+        let cache = crate::tests::test_cache("backup-identity-only", |config| {
+            config.backup_identity_file = Some("unused-identity".into());
+        })
+        .await;
+        assert!(!cache.uses_backup_archive());
+        cache.set("key", b"value", None).await.unwrap();
+        cache.backup().await.unwrap();
+        assert!(!cache.cache_dir.join(BACKUP_ARCHIVE_FILE).exists());
+        assert!(cache.cache_dir.join(crate::BACKUP_DIR).exists());
+        std::fs::remove_dir_all(&cache.cache_dir).unwrap();
     }
 }
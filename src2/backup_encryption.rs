@@ -0,0 +1,91 @@
+// Public-key encryption of backup archives in the age format.
+//
+// When recipients are configured, archives are encrypted to their X25519
+// public keys (`age1...`) instead of the live keyring, so a backup host only
+// ever holds public keys. Restoring needs a matching private key
+// (`AGE-SECRET-KEY-1...`) from an identity file that is kept apart from the
+// cache and only read for the duration of a restore. Archives are plain age
+// files, so `age -d -i <identity>` can open them as well.
+
+use std::{
+    io::{self, Read, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use age::x25519::{Identity, Recipient};
+use tokio::fs;
+use zeroize::Zeroizing;
+
+use crate::{key_material::check_key_file, CacheError};
+
+const AGE_HEADER: &[u8] = b"age-encryption.org/v1\n";
+
+pub(crate) fn parse_recipients(recipients: &[String]) -> Result<Vec<Recipient>, CacheError> {
+    recipients
+        .iter()
+        .map(|recipient| {
+            Recipient::from_str(recipient.trim())
+                .map_err(|e| CacheError::KeyError(format!("invalid backup recipient '{}': {}", recipient, e)))
+        })
+        .collect()
+}
+
+pub(crate) fn is_public_key_encrypted(archive: &[u8]) -> bool {
+    archive.starts_with(AGE_HEADER)
+}
+
+// Encrypts archive records to the recipients as they arrive, wiping each one
+// once it has been written, so the plaintext archive never exists in one piece.
+pub(crate) fn encrypt_records_to(
+    recipients: &[Recipient],
+    output: &mut impl Write,
+    records: impl Iterator<Item = Zeroizing<Vec<u8>>>,
+) -> io::Result<()> {
+    let recipients = recipients
+        .iter()
+        .map(|recipient| Box::new(recipient.clone()) as Box<dyn age::Recipient + Send>)
+        .collect();
+    let encryptor = age::Encryptor::with_recipients(recipients)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no backup recipients configured"))?;
+
+    let mut writer = encryptor.wrap_output(output).map_err(io::Error::other)?;
+    for record in records {
+        writer.write_all(&record)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+// Tries every identity in `identity_file` against the archive.
+pub(crate) async fn decrypt_with(identity_file: &Path, archive: &[u8]) -> Result<Vec<u8>, CacheError> {
+    check_key_file(identity_file).await?;
+    let contents = Zeroizing::new(fs::read_to_string(identity_file).await.map_err(CacheError::IoError)?);
+    let identities = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            Identity::from_str(line).map_err(|e| {
+                CacheError::KeyError(format!("invalid identity in {}: {}", identity_file.display(), e))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if identities.is_empty() {
+        return Err(CacheError::KeyError(format!("no identities in {}", identity_file.display())));
+    }
+
+    let decryptor = match age::Decryptor::new(archive).map_err(|_| CacheError::IntegrityError)? {
+        age::Decryptor::Recipients(decryptor) => decryptor,
+        _ => return Err(CacheError::KeyError("backup is passphrase-encrypted, not to recipients".to_string())),
+    };
+    let mut reader = decryptor
+        .decrypt(identities.iter().map(|identity| identity as &dyn age::Identity))
+        .map_err(|_| CacheError::DecryptionError)?;
+    let mut output = Vec::new();
+    reader.read_to_end(&mut output).map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidData => CacheError::IntegrityError,
+        _ => CacheError::IoError(e),
+    })?;
+    Ok(output)
+}
@@ -0,0 +1,267 @@
+// Background compaction of the segment store.
+//
+// Overwrites, deletes and expiries leave dead records behind in sealed
+// segments. The store tracks how many of each segment's bytes are dead; once a
+// segment's garbage ratio crosses the configured threshold its live records are
+// copied into the active segment and the file is deleted. Copying is paced to a
+// byte rate so compaction never starves foreground IO.
+
+use std::{
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::{sync::Mutex, time};
+
+use crate::{CacheError, DiskCache};
+
+// Paces IO to an average byte rate; a rate of 0 means unthrottled.
+pub(crate) struct Throttle {
+    bytes_per_sec: u64,
+    started: Instant,
+    consumed: u64,
+}
+
+impl Throttle {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        Throttle {
+            bytes_per_sec,
+            started: Instant::now(),
+            consumed: 0,
+        }
+    }
+
+    pub(crate) async fn consume(&mut self, bytes: u64) {
+        if self.bytes_per_sec == 0 {
+            return;
+        }
+        self.consumed += bytes;
+        let due = Duration::from_secs_f64(self.consumed as f64 / self.bytes_per_sec as f64);
+        let elapsed = self.started.elapsed();
+        if due > elapsed {
+            time::sleep(due - elapsed).await;
+        }
+    }
+}
+
+pub(crate) struct Compaction {
+    garbage_ratio: f64,
+    max_bytes_per_sec: u64,
+    running: Mutex<()>, // One pass at a time, whether periodic or requested
+    runs: AtomicU64,
+    segments_compacted: AtomicU64,
+    bytes_reclaimed: AtomicU64,
+    bytes_rewritten: AtomicU64,
+    current_segment: AtomicU32, // 0 while idle; segment ids start at 1
+    current_bytes_total: AtomicU64,
+    current_bytes_done: AtomicU64,
+}
+
+impl Compaction {
+    pub(crate) fn new(garbage_ratio: f64, max_bytes_per_sec: u64) -> Self {
+        Compaction {
+            garbage_ratio,
+            max_bytes_per_sec,
+            running: Mutex::new(()),
+            runs: AtomicU64::new(0),
+            segments_compacted: AtomicU64::new(0),
+            bytes_reclaimed: AtomicU64::new(0),
+            bytes_rewritten: AtomicU64::new(0),
+            current_segment: AtomicU32::new(0),
+            current_bytes_total: AtomicU64::new(0),
+            current_bytes_done: AtomicU64::new(0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct CompactionProgress {
+    pub(crate) segment_id: u32,
+    pub(crate) bytes_done: u64,
+    pub(crate) bytes_total: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct CompactionReport {
+    pub(crate) runs: u64,
+    pub(crate) segments_compacted: u64,
+    pub(crate) bytes_reclaimed: u64,
+    pub(crate) bytes_rewritten: u64,
+    pub(crate) dead_bytes: u64, // Still waiting to be reclaimed
+    pub(crate) in_progress: Option<CompactionProgress>,
+}
+
+impl DiskCache {
+    // Compacts every sealed segment over the garbage threshold, oldest first.
+    pub(crate) async fn compact(&self) -> Result<(), CacheError> {
+        self.compact_segments(self.compaction.garbage_ratio).await
+    }
+
+    // Also used by eviction, with any garbage at all, once the disk budget is exceeded.
+    pub(crate) async fn compact_segments(&self, min_garbage_ratio: f64) -> Result<(), CacheError> {
+        let compaction = &self.compaction;
+        let _running = compaction.running.lock().await;
+        compaction.runs.fetch_add(1, Ordering::Relaxed);
+
+        // One throttle for the whole pass so the rate holds across segments
+        let mut throttle = Throttle::new(compaction.max_bytes_per_sec);
+        for (segment_id, usage) in self.store.segment_usage().await {
+            if usage.dead == 0 || usage.garbage_ratio() < min_garbage_ratio {
+                continue;
+            }
+
+            compaction.current_bytes_total.store(usage.len, Ordering::Relaxed);
+            compaction.current_bytes_done.store(0, Ordering::Relaxed);
+            compaction.current_segment.store(segment_id, Ordering::Relaxed);
+            let result = self
+                .store
+                .compact_segment(segment_id, &mut throttle, &compaction.current_bytes_done)
+                .await;
+            compaction.current_segment.store(0, Ordering::Relaxed);
+
+            let compacted = result.map_err(CacheError::IoError)?;
+            compaction.segments_compacted.fetch_add(1, Ordering::Relaxed);
+            compaction.bytes_reclaimed.fetch_add(compacted.reclaimed, Ordering::Relaxed);
+            compaction.bytes_rewritten.fetch_add(compacted.rewritten, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn compaction_report(&self) -> CompactionReport {
+        let compaction = &self.compaction;
+        let in_progress = match compaction.current_segment.load(Ordering::Relaxed) {
+            0 => None,
+            segment_id => Some(CompactionProgress {
+                segment_id,
+                bytes_done: compaction.current_bytes_done.load(Ordering::Relaxed),
+                bytes_total: compaction.current_bytes_total.load(Ordering::Relaxed),
+            }),
+        };
+        CompactionReport {
+            runs: compaction.runs.load(Ordering::Relaxed),
+            segments_compacted: compaction.segments_compacted.load(Ordering::Relaxed),
+            bytes_reclaimed: compaction.bytes_reclaimed.load(Ordering::Relaxed),
+            bytes_rewritten: compaction.bytes_rewritten.load(Ordering::Relaxed),
+            dead_bytes: self.store.dead_bytes().await,
+            in_progress,
+        }
+    }
+}
+
+pub(crate) async fn periodic_compaction(storage: Arc<DiskCache>, interval: Duration) {
+    let mut interval_timer = time::interval(interval);
+    loop {
+        interval_timer.tick().await;
+        if let Err(e) = storage.compact().await {
+            println!("Compaction failed: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::{
+        io_backend::{new_backend, IoBackendKind},
+        segment_store::SegmentStore,
+    };
+
+    async fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("trust-compaction-{}-{}", std::process::id(), name));
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        dir
+    }
+
+    // Segments smaller than one record, so every record but a tombstone seals its own
+    async fn open_store(dir: &Path) -> SegmentStore {
+        SegmentStore::open(dir, 64, new_backend(IoBackendKind::TokioFs, 0)).await.unwrap()
+    }
+
+    async fn value_of(store: &SegmentStore, key: &str) -> Option<Vec<u8>> {
+        store.get(key).await.unwrap().map(|record| record.value)
+    }
+
+    #[tokio::test]
+    async fn keeps_live_entries_and_needed_tombstones() {
+        let dir = test_dir("live-and-tombstones").await;
+        let oldest;
+        {
+            let store = open_store(&dir).await;
+            store.put("deleted", &[1; 100], None).await.unwrap();
+            store.put("overwritten", &[2; 100], None).await.unwrap();
+            store.delete("deleted").await.unwrap();
+            store.put("overwritten", &[3; 100], None).await.unwrap();
+            store.put("kept", &[4; 100], Some(i64::MAX)).await.unwrap();
+            store.put("filler", &[5; 100], None).await.unwrap();
+
+            // Leaves the oldest segment, which holds the deleted put, so the tombstone is still needed
+            let segments = store.segment_usage().await;
+            oldest = segments.iter().map(|(segment_id, _)| *segment_id).min().unwrap();
+            let mut compacted = 0;
+            for (segment_id, usage) in segments {
+                if segment_id != oldest && usage.dead > 0 {
+                    let progress = AtomicU64::new(0);
+                    store.compact_segment(segment_id, &mut Throttle::new(0), &progress).await.unwrap();
+                    compacted += 1;
+                }
+            }
+            assert!(compacted > 0);
+            assert!(store.segment_usage().await.iter().any(|(segment_id, _)| *segment_id == oldest));
+            store.flush().await.unwrap();
+        }
+
+        let store = open_store(&dir).await;
+        assert_eq!(value_of(&store, "deleted").await, None);
+        assert_eq!(value_of(&store, "overwritten").await, Some(vec![3; 100]));
+        assert_eq!(store.get("kept").await.unwrap().unwrap().expiry, Some(i64::MAX));
+        assert_eq!(value_of(&store, "filler").await, Some(vec![5; 100]));
+
+        // Compacting the old put away as well leaves the copied tombstone nothing to shadow
+        let progress = AtomicU64::new(0);
+        store.compact_segment(oldest, &mut Throttle::new(0), &progress).await.unwrap();
+        assert!(store.segment_usage().await.iter().all(|(segment_id, _)| *segment_id != oldest));
+        assert_eq!(value_of(&store, "deleted").await, None);
+        assert_eq!(value_of(&store, "overwritten").await, Some(vec![3; 100]));
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn throttle_paces_to_its_rate() {
+        let started = Instant::now();
+        let mut unthrottled = Throttle::new(0);
+        unthrottled.consume(u64::MAX / 2).await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        let mut throttle = Throttle::new(1000);
+        throttle.consume(100).await;
+        throttle.consume(100).await;
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn compaction_copies_at_the_throttled_rate() {
+        let dir = test_dir("throttled").await;
+        let store = open_store(&dir).await;
+        store.put("a", &[1; 100], None).await.unwrap();
+        store.put("b", &[2; 100], None).await.unwrap();
+        store.put("b", &[3; 100], None).await.unwrap();
+        store.put("filler", &[4; 100], None).await.unwrap();
+
+        // The first segment holds only `a`, which is live and has to be copied
+        let (segment_id, _) = store.segment_usage().await.into_iter().min_by_key(|(segment_id, _)| *segment_id).unwrap();
+        let started = Instant::now();
+        let progress = AtomicU64::new(0);
+        let compacted = store.compact_segment(segment_id, &mut Throttle::new(1000), &progress).await.unwrap();
+        assert!(compacted.rewritten > 0);
+        assert!(started.elapsed() >= Duration::from_secs_f64(compacted.rewritten as f64 / 1000.0));
+        assert_eq!(value_of(&store, "a").await, Some(vec![1; 100]));
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
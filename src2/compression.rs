@@ -0,0 +1,251 @@
+// Per-entry compression of cache values.
+//
+// Values are compressed before they are encrypted or checksummed, so the
+// memory tier, the segment store, the WAL and backups all hold the smaller
+// form. Each compressed value carries a small header naming its codec, so the
+// codec, the threshold or compression as a whole can be changed at any time:
+// new writes follow the settings while existing entries keep decoding with
+// whatever they were written with. Encryption hides the content but not the
+// compressed length, so values that mix secrets with attacker-chosen data are
+// better kept in a cache with compression off.
+//
+// Values below `compression_min_bytes` are stored as they are. Larger ones are
+// compressed only when it pays: a value that shrinks by less than an eighth is
+// stored as it is, and for large values a leading sample is compressed first so
+// already-compressed data (images, archives, ciphertext) is skipped without
+// running the codec over all of it.
+//
+// Values without the header are stored as they are, including everything
+// written before compression existed. An uncompressed value that happens to
+// start with the magic gets a header with codec `none`, so it is never
+// mistaken for a compressed one.
+//
+// Layout (integers little endian):
+//   magic: b"TRCZ" | codec: u8 | original_len: u32 | payload
+
+use std::{
+    borrow::Cow,
+    io::{Read, Write},
+};
+
+use bytes::Bytes;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+
+use crate::{mapped_segments::ValueGuard, CacheError};
+
+const MAGIC: &[u8; 4] = b"TRCZ";
+const HEADER_LEN: usize = 4 + 1 + 4;
+const SAMPLE_LEN: usize = 16 * 1024;
+const MIN_SAVING_DIVISOR: usize = 8; // Compression must save at least 1/8 of the bytes
+
+// IDs are part of the on-disk format and must never be reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Codec {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+    Gzip,
+}
+
+impl Codec {
+    fn id(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Zstd => 1,
+            Codec::Lz4 => 2,
+            Codec::Gzip => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Zstd),
+            2 => Some(Codec::Lz4),
+            3 => Some(Codec::Gzip),
+            _ => None,
+        }
+    }
+
+    fn compress(self, data: &[u8], level: Option<i32>) -> Result<Vec<u8>, CacheError> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Zstd => {
+                zstd::bulk::compress(data, level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL)).map_err(CacheError::IoError)
+            }
+            Codec::Lz4 => Ok(lz4_flex::block::compress(data)), // Has no levels
+            Codec::Gzip => {
+                let level = level.map_or(Compression::default(), |level| Compression::new(level.clamp(0, 9) as u32));
+                let mut encoder = GzEncoder::new(Vec::with_capacity(data.len() / 2), level);
+                encoder.write_all(data).map_err(CacheError::IoError)?;
+                encoder.finish().map_err(CacheError::IoError)
+            }
+        }
+    }
+
+    fn decompress(self, payload: &[u8], original_len: usize) -> Result<Vec<u8>, CacheError> {
+        let value = match self {
+            Codec::None => payload.to_vec(),
+            Codec::Zstd => zstd::bulk::decompress(payload, original_len).map_err(|_| CacheError::IntegrityError)?,
+            Codec::Lz4 => lz4_flex::block::decompress(payload, original_len).map_err(|_| CacheError::IntegrityError)?,
+            Codec::Gzip => {
+                let mut value = Vec::with_capacity(original_len);
+                GzDecoder::new(payload)
+                    .take(original_len as u64 + 1)
+                    .read_to_end(&mut value)
+                    .map_err(|_| CacheError::IntegrityError)?;
+                value
+            }
+        };
+        if value.len() != original_len {
+            return Err(CacheError::IntegrityError);
+        }
+        Ok(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CompressionSettings {
+    pub(crate) codec: Codec,
+    pub(crate) min_bytes: usize,
+    pub(crate) level: Option<i32>, // Codec default when unset
+}
+
+// Returns the value to store: compressed with a header, or as it is.
+pub(crate) fn compress<'a>(value: &'a [u8], settings: &CompressionSettings) -> Result<Cow<'a, [u8]>, CacheError> {
+    let worthwhile = settings.codec != Codec::None
+        && value.len() >= settings.min_bytes
+        && value.len() <= u32::MAX as usize
+        && (value.len() < 2 * SAMPLE_LEN || saves_enough(&value[..SAMPLE_LEN], settings)?);
+    if worthwhile {
+        let payload = settings.codec.compress(value, settings.level)?;
+        if HEADER_LEN + payload.len() <= value.len() - value.len() / MIN_SAVING_DIVISOR {
+            return Ok(Cow::Owned(with_header(settings.codec, value.len(), &payload)));
+        }
+    }
+    if value.starts_with(MAGIC) {
+        return Ok(Cow::Owned(with_header(Codec::None, value.len(), value)));
+    }
+    Ok(Cow::Borrowed(value))
+}
+
+pub(crate) fn decompress(stored: &[u8]) -> Result<Cow<'_, [u8]>, CacheError> {
+    if !stored.starts_with(MAGIC) {
+        return Ok(Cow::Borrowed(stored));
+    }
+    let header = stored.get(..HEADER_LEN).ok_or(CacheError::IntegrityError)?;
+    let codec = Codec::from_id(header[4]).ok_or(CacheError::IntegrityError)?;
+    let original_len = u32::from_le_bytes(header[5..9].try_into().unwrap()) as usize;
+    let payload = &stored[HEADER_LEN..];
+    match codec {
+        Codec::None if payload.len() == original_len => Ok(Cow::Borrowed(payload)),
+        codec => Ok(Cow::Owned(codec.decompress(payload, original_len)?)),
+    }
+}
+
+pub(crate) fn is_compressed(stored: &[u8]) -> bool {
+    stored.starts_with(MAGIC)
+}
+
+// Uncompressed values stay borrowed; compressed ones are inflated into a fresh buffer.
+pub(crate) fn decompress_guard(value: ValueGuard) -> Result<ValueGuard, CacheError> {
+    if !is_compressed(&value) {
+        return Ok(value);
+    }
+    Ok(ValueGuard::Shared(Bytes::from(decompress(&value)?.into_owned())))
+}
+
+fn saves_enough(sample: &[u8], settings: &CompressionSettings) -> Result<bool, CacheError> {
+    let compressed = settings.codec.compress(sample, settings.level)?;
+    Ok(compressed.len() <= sample.len() - sample.len() / MIN_SAVING_DIVISOR)
+}
+
+fn with_header(codec: Codec, original_len: usize, payload: &[u8]) -> Vec<u8> {
+    let mut stored = Vec::with_capacity(HEADER_LEN + payload.len());
+    stored.extend_from_slice(MAGIC);
+    stored.push(codec.id());
+    stored.extend_from_slice(&(original_len as u32).to_le_bytes());
+    stored.extend_from_slice(payload);
+    stored
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(codec: Codec) -> CompressionSettings {
+        CompressionSettings {
+            codec,
+            min_bytes: 64,
+            level: None,
+        }
+    }
+
+    fn compressible() -> Vec<u8> {
+        b"the quick brown fox jumps over the lazy dog ".repeat(100)
+    }
+
+    #[test]
+    fn round_trips_every_codec() {
+        let value = compressible();
+        for codec in [Codec::Zstd, Codec::Lz4, Codec::Gzip] {
+            let stored = compress(&value, &settings(codec)).unwrap();
+            assert!(is_compressed(&stored), "{:?}", codec);
+            assert!(stored.len() < value.len(), "{:?}", codec);
+            assert_eq!(decompress(&stored).unwrap(), &value[..], "{:?}", codec);
+        }
+    }
+
+    #[test]
+    fn leaves_small_and_incompressible_values_alone() {
+        let small = b"short value";
+        assert!(matches!(compress(small, &settings(Codec::Zstd)).unwrap(), Cow::Borrowed(_)));
+        assert_eq!(decompress(small).unwrap(), &small[..]);
+
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        assert!(!is_compressed(&compress(&noise, &settings(Codec::Zstd)).unwrap()));
+    }
+
+    #[test]
+    fn escapes_values_that_start_with_the_magic() {
+        let mut value = MAGIC.to_vec();
+        value.extend_from_slice(b"not a header");
+        let stored = compress(&value, &settings(Codec::None)).unwrap();
+        assert_eq!(stored[4], Codec::None.id());
+        assert_eq!(decompress(&stored).unwrap(), &value[..]);
+    }
+
+    #[test]
+    fn rejects_truncation() {
+        let value = compressible();
+        for codec in [Codec::Zstd, Codec::Lz4, Codec::Gzip] {
+            let stored = compress(&value, &settings(codec)).unwrap();
+            for len in MAGIC.len()..stored.len() {
+                assert!(
+                    matches!(decompress(&stored[..len]), Err(CacheError::IntegrityError)),
+                    "{:?} at length {}",
+                    codec,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_codec() {
+        let mut stored = compress(&compressible(), &settings(Codec::Zstd)).unwrap().into_owned();
+        stored[4] = 0xFF;
+        assert!(matches!(decompress(&stored), Err(CacheError::IntegrityError)));
+    }
+}
@@ -1,51 +1,752 @@
-use aes_gcm::{Aes256Gcm, aead::{Aead, NewAead, generic_array::GenericArray}};
-use aes_gcm::aead::Error as AeadError;
+// Keyring-backed envelope encryption for cache values and backups.
+//
+// Each value is encrypted under its own random data key, and only that data key
+// is wrapped by a keyring key. Every ciphertext is a binary envelope (see
+// envelope.rs) naming the wrapping key, so keys can be rotated without losing
+// data: new writes use the current key while older keys stay available until a
+// re-encryption pass has rewrapped every data key and they are retired.
+// Rewrapping only touches the envelope header, never the value's own
+// ciphertext. The keyring is persisted with an atomic write before a new key is
+// ever used.
+//
+// Values are encrypted with the AEAD picked in the config: AES-256-GCM,
+// AES-256-GCM-SIV, ChaCha20-Poly1305 or XChaCha20-Poly1305. The envelope records
+// which one, so changing the setting only affects new writes and stores holding
+// a mix keep decrypting. Data keys and the keyring itself are always wrapped
+// with AES-256-GCM. Since every data key encrypts exactly one value, random
+// nonces never repeat under a key; SIV and the 192-bit XChaCha nonces are
+// there for deployments that want a margin beyond that.
+//
+// A value's ciphertext is bound to where it belongs: the envelope version and
+// algorithm, the namespace and the entry key are authenticated as associated
+// data. A
+// ciphertext copied under another key or namespace still unwraps its data key
+// but fails the tag check on the value, which is reported as a context
+// mismatch instead of a plain decryption failure. The wrapped data key itself
+// carries no associated data, which is what lets rewrapping ignore the context.
+//
+// The keyring file is sealed with AES-256-GCM under a master key handed out by
+// a key provider (see master_key.rs), so it is useless on its own. A wrong
+// master key or passphrase fails the tag check and is reported as such rather
+// than as a corrupt keyring. When a refresh finds the provider handing out a
+// new master key, the keyring is resealed under it; the keyring keys, and so
+// every stored value, stay as they are. With an ephemeral provider the keyring
+// only ever lives in memory. Key bytes, including each value's data key,
+// only ever live in wiped-on-drop buffers (see key_material.rs).
+//
+// Tenants get keys of their own, kept in the same keyring (see tenants.rs).
+// A tenant's data keys are wrapped by its key rather than the current one, and
+// the envelope names it by the tenant's ID with the top bit set. Tenant keys
+// are not rotated with the keyring. Destroying one leaves its tenant's
+// ciphertexts unreadable for good, and its ID and name are kept so neither is
+// ever handed out again.
+//
+// Keyrings used to import a pre-existing key file as legacy key 1, which put
+// the master key itself to work wrapping data keys. Such keyrings are refused;
+// the key flags byte is kept for the format but must be zero.
+//
+// Wrapped data key:   nonce: [u8; 12] | encrypted key: [u8; 32] | tag: [u8; 16]
+// Keyring file:       nonce: [u8; 12] | sealed(keyring) and tag
+// Associated data:    version: u8 | algorithm: u8 | namespace_len: u32 | namespace | key
+// Keyring:            current: u32 | count: u32 | (key_id: u32 | flags: u8 | key: [u8; 32])*
+//                     | [tenant_count: u32 | next_tenant_id: u32
+//                        | (tenant_id: u32 | flags: u8 | name_len: u16 | name | key: [u8; 32] unless shredded)*]
+
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use aes_gcm::{
+    aead::{self, Aead, AeadCore, KeyInit, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use aes_gcm_siv::Aes256GcmSiv;
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
 use rand::{rngs::OsRng, RngCore};
-use std::path::PathBuf;
-use std::fs::{self, File};
-use std::io::{Read, Write};
+use tokio::{fs, sync::Mutex};
+use zeroize::Zeroizing;
+
+use crate::{
+    atomic_write,
+    envelope::{self, Algorithm, Envelope, EnvelopeError},
+    key_material::{check_key_file, create_key_dir, SecretBytes},
+    master_key::{KeyProvider, MasterKey},
+    tenants::TENANT_SEPARATOR,
+    CacheError,
+};
+
+const KEYRING_FILE: &str = "encryption_keyring.bin";
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const KEYRING_HEADER_LEN: usize = 8;
+const KEYRING_ENTRY_LEN: usize = 4 + 1 + KEY_LEN;
+const FLAG_LEGACY: u8 = 1; // Only recognised to refuse it
+const FLAG_SHREDDED: u8 = 1;
+const TENANT_SECTION_HEADER_LEN: usize = 8;
+const TENANT_ENTRY_HEADER_LEN: usize = 4 + 1 + 2;
+const TENANT_KEY_FLAG: u32 = 1 << 31; // Set in envelope key IDs that name a tenant key
 
 #[derive(Debug)]
-pub enum EncryptionError {
-    IoError(std::io::Error),
-    AeadError(AeadError),
+pub(crate) enum EncryptionError {
+    IoError(io::Error),
+    EncryptFailed,
+    DecryptFailed,
+    UnknownKey(u32),
+    CurrentKey(u32),
+    InvalidKeyring(String),
+    InvalidKeyMaterial(String),
+    WrongMasterKey,
+    ContextMismatch,
+    InvalidEnvelope(EnvelopeError),
+    InsecureKeyFile(String),
+    InvalidTenant(String),
+    UnknownTenant(String),
+    ShreddedTenant(String),
 }
 
-impl From<std::io::Error> for EncryptionError {
-    fn from(error: std::io::Error) -> Self {
+impl From<io::Error> for EncryptionError {
+    fn from(error: io::Error) -> Self {
         EncryptionError::IoError(error)
     }
 }
 
-impl From<AeadError> for EncryptionError {
-    fn from(error: AeadError) -> Self {
-        EncryptionError::AeadError(error)
+impl From<EnvelopeError> for EncryptionError {
+    fn from(error: EnvelopeError) -> Self {
+        EncryptionError::InvalidEnvelope(error)
+    }
+}
+
+impl fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncryptionError::IoError(e) => write!(f, "key storage error: {}", e),
+            EncryptionError::EncryptFailed => write!(f, "encryption failed"),
+            EncryptionError::DecryptFailed => write!(f, "decryption failed"),
+            EncryptionError::UnknownKey(id) => write!(f, "no key with ID {} in the keyring", id),
+            EncryptionError::CurrentKey(id) => write!(f, "key {} is the current key and can't be retired", id),
+            EncryptionError::InvalidKeyring(reason) => write!(f, "invalid keyring: {}", reason),
+            EncryptionError::InvalidKeyMaterial(reason) => write!(f, "invalid master key: {}", reason),
+            EncryptionError::WrongMasterKey => {
+                write!(f, "the keyring can't be unsealed; wrong master key or passphrase")
+            }
+            EncryptionError::ContextMismatch => {
+                write!(f, "ciphertext does not belong to this entry or has been altered")
+            }
+            EncryptionError::InvalidEnvelope(e) => write!(f, "{}", e),
+            EncryptionError::InsecureKeyFile(reason) => write!(f, "refusing insecure key file: {}", reason),
+            EncryptionError::InvalidTenant(reason) => write!(f, "invalid tenant name: {}", reason),
+            EncryptionError::UnknownTenant(tenant) => write!(f, "no tenant '{}' in the keyring", tenant),
+            EncryptionError::ShreddedTenant(tenant) => {
+                write!(f, "the key of tenant '{}' has been destroyed; its data is gone for good", tenant)
+            }
+        }
+    }
+}
+
+impl From<EncryptionError> for CacheError {
+    fn from(error: EncryptionError) -> Self {
+        match error {
+            EncryptionError::IoError(e) => CacheError::IoError(e),
+            EncryptionError::EncryptFailed => CacheError::EncryptionError,
+            EncryptionError::DecryptFailed => CacheError::DecryptionError,
+            EncryptionError::ContextMismatch | EncryptionError::InvalidEnvelope(_) => CacheError::IntegrityError,
+            other => CacheError::KeyError(other.to_string()),
+        }
+    }
+}
+
+// What a value's ciphertext is bound to.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct EntryContext<'a> {
+    pub(crate) namespace: &'a str,
+    pub(crate) key: &'a str,
+}
+
+impl EntryContext<'_> {
+    fn aad(&self, algorithm: Algorithm) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(2 + 4 + self.namespace.len() + self.key.len());
+        aad.push(envelope::VERSION);
+        aad.push(algorithm.id());
+        aad.extend_from_slice(&(self.namespace.len() as u32).to_le_bytes());
+        aad.extend_from_slice(self.namespace.as_bytes());
+        aad.extend_from_slice(self.key.as_bytes());
+        aad
+    }
+}
+
+// Only the key bytes are kept, in the locked arena. The expanded AES key
+// schedule is built for each use and wiped when it is dropped, so no copy of it
+// outlives the call.
+#[derive(Clone)]
+struct KeyEntry {
+    key: SecretBytes,
+}
+
+struct MasterCipher {
+    key: SecretBytes, // Compared against what the provider hands out on refresh
+}
+
+impl MasterCipher {
+    fn new(key: SecretBytes) -> Result<Self, EncryptionError> {
+        if key.expose().len() != KEY_LEN {
+            return Err(EncryptionError::InvalidKeyMaterial(format!("master keys must be {} bytes", KEY_LEN)));
+        }
+        Ok(MasterCipher { key })
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(self.key.expose()))
+    }
+}
+
+impl KeyEntry {
+    fn new(key: SecretBytes) -> Result<Self, EncryptionError> {
+        if key.expose().len() != KEY_LEN {
+            return Err(EncryptionError::InvalidKeyring(format!("keys must be {} bytes", KEY_LEN)));
+        }
+        Ok(KeyEntry { key })
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(self.key.expose()))
+    }
+
+    fn generate() -> Result<Self, EncryptionError> {
+        KeyEntry::new(SecretBytes::random(KEY_LEN))
     }
 }
 
-fn generate_nonce() -> [u8; 12] {
-    let mut nonce = [0u8; 12];
-    OsRng.fill_bytes(&mut nonce);
-    nonce
+#[derive(Clone)]
+struct TenantKey {
+    id: u32,
+    entry: Option<KeyEntry>, // None once shredded
 }
 
-struct EncryptionService {
-    cipher: Aes256Gcm,
+#[derive(Clone)]
+struct Keyring {
+    current: u32,
+    keys: BTreeMap<u32, KeyEntry>,
+    tenants: BTreeMap<String, TenantKey>,
+    next_tenant_id: u32,
+}
+
+impl Keyring {
+    fn encode(&self) -> Zeroizing<Vec<u8>> {
+        // Sized exactly: growing the buffer would leave unwiped copies of the
+        // keys in the freed allocations
+        let tenants_len: usize = self
+            .tenants
+            .iter()
+            .map(|(name, tenant)| TENANT_ENTRY_HEADER_LEN + name.len() + tenant.entry.as_ref().map_or(0, |_| KEY_LEN))
+            .sum();
+        let mut buffer = Zeroizing::new(Vec::with_capacity(
+            KEYRING_HEADER_LEN + self.keys.len() * KEYRING_ENTRY_LEN + TENANT_SECTION_HEADER_LEN + tenants_len,
+        ));
+        buffer.extend_from_slice(&self.current.to_le_bytes());
+        buffer.extend_from_slice(&(self.keys.len() as u32).to_le_bytes());
+        for (id, entry) in &self.keys {
+            buffer.extend_from_slice(&id.to_le_bytes());
+            buffer.push(0);
+            buffer.extend_from_slice(entry.key.expose());
+        }
+        buffer.extend_from_slice(&(self.tenants.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(&self.next_tenant_id.to_le_bytes());
+        for (name, tenant) in &self.tenants {
+            buffer.extend_from_slice(&tenant.id.to_le_bytes());
+            buffer.push(if tenant.entry.is_none() { FLAG_SHREDDED } else { 0 });
+            buffer.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            buffer.extend_from_slice(name.as_bytes());
+            if let Some(entry) = &tenant.entry {
+                buffer.extend_from_slice(entry.key.expose());
+            }
+        }
+        buffer
+    }
+
+    fn decode(buffer: &[u8]) -> Result<Self, EncryptionError> {
+        let invalid = |reason: &str| EncryptionError::InvalidKeyring(reason.to_string());
+        let header = buffer.get(..KEYRING_HEADER_LEN).ok_or_else(|| invalid("truncated header"))?;
+        let current = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let count = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        let keys_end = KEYRING_HEADER_LEN + count * KEYRING_ENTRY_LEN;
+        if buffer.len() < keys_end {
+            return Err(invalid("length does not match key count"));
+        }
+
+        let mut keys = BTreeMap::new();
+        for entry in buffer[KEYRING_HEADER_LEN..keys_end].chunks_exact(KEYRING_ENTRY_LEN) {
+            let id = u32::from_le_bytes(entry[0..4].try_into().unwrap());
+            if entry[4] & FLAG_LEGACY != 0 {
+                return Err(invalid("legacy keys imported from a pre-keyring key file are no longer supported"));
+            }
+            keys.insert(id, KeyEntry::new(SecretBytes::from_slice(&entry[5..]))?);
+        }
+        if !keys.contains_key(&current) {
+            return Err(invalid("current key is missing"));
+        }
+
+        // Keyrings written before tenants existed end here
+        let mut tenants = BTreeMap::new();
+        let mut next_tenant_id = 1;
+        if buffer.len() > keys_end {
+            let truncated = || invalid("truncated tenant keys");
+            let header = buffer
+                .get(keys_end..keys_end + TENANT_SECTION_HEADER_LEN)
+                .ok_or_else(truncated)?;
+            let tenant_count = u32::from_le_bytes(header[0..4].try_into().unwrap());
+            next_tenant_id = u32::from_le_bytes(header[4..8].try_into().unwrap());
+            let mut cursor = keys_end + TENANT_SECTION_HEADER_LEN;
+            for _ in 0..tenant_count {
+                let header = buffer
+                    .get(cursor..cursor + TENANT_ENTRY_HEADER_LEN)
+                    .ok_or_else(truncated)?;
+                let id = u32::from_le_bytes(header[0..4].try_into().unwrap());
+                let shredded = header[4] & FLAG_SHREDDED != 0;
+                let name_len = u16::from_le_bytes(header[5..7].try_into().unwrap()) as usize;
+                cursor += TENANT_ENTRY_HEADER_LEN;
+                let name = buffer.get(cursor..cursor + name_len).ok_or_else(truncated)?;
+                let name = String::from_utf8(name.to_vec()).map_err(|_| invalid("tenant name is not UTF-8"))?;
+                cursor += name_len;
+                let entry = if shredded {
+                    None
+                } else {
+                    let key = buffer.get(cursor..cursor + KEY_LEN).ok_or_else(truncated)?;
+                    cursor += KEY_LEN;
+                    Some(KeyEntry::new(SecretBytes::from_slice(key))?)
+                };
+                if id >= next_tenant_id || id & TENANT_KEY_FLAG != 0 {
+                    return Err(invalid("tenant ID out of range"));
+                }
+                tenants.insert(name, TenantKey { id, entry });
+            }
+            if cursor != buffer.len() {
+                return Err(invalid("length does not match tenant count"));
+            }
+        }
+        Ok(Keyring {
+            current,
+            keys,
+            tenants,
+            next_tenant_id,
+        })
+    }
+
+    // The key that wraps data keys under envelope key ID `key_id`.
+    fn wrapping_key(&self, key_id: u32) -> Result<&KeyEntry, EncryptionError> {
+        if key_id & TENANT_KEY_FLAG == 0 {
+            return self.keys.get(&key_id).ok_or(EncryptionError::UnknownKey(key_id));
+        }
+        let (name, tenant) = self
+            .tenants
+            .iter()
+            .find(|(_, tenant)| tenant.id == key_id & !TENANT_KEY_FLAG)
+            .ok_or(EncryptionError::UnknownKey(key_id))?;
+        tenant
+            .entry
+            .as_ref()
+            .ok_or_else(|| EncryptionError::ShreddedTenant(name.clone()))
+    }
+
+    fn seal(&self, master: &Aes256Gcm) -> Result<Vec<u8>, EncryptionError> {
+        seal(master, &self.encode(), &[])
+    }
+
+    fn unseal(master: &Aes256Gcm, buffer: &[u8]) -> Result<Self, EncryptionError> {
+        if buffer.len() < NONCE_LEN {
+            return Err(EncryptionError::InvalidKeyring("truncated file".to_string()));
+        }
+        let plaintext = Zeroizing::new(open(master, buffer, &[]).map_err(|_| EncryptionError::WrongMasterKey)?);
+        Keyring::decode(&plaintext)
+    }
+}
+
+pub(crate) struct EncryptionService {
+    keyring_path: Option<PathBuf>, // None for an ephemeral master key
+    algorithm: Algorithm, // For new values; existing ones keep what their envelope records
+    provider: Box<dyn KeyProvider>,
+    master: RwLock<MasterCipher>,
+    keyring: RwLock<Arc<Keyring>>, // Replaced as a whole; readers share it instead of copying the keys
+    changes: Mutex<()>, // Serialises rotate/retire/refresh so each persists a complete keyring
 }
 
 impl EncryptionService {
-    async fn new(key_path: &PathBuf) -> Result<Self, EncryptionError> {
-        let key = if key_path.exists() {
-            tokio::fs::read(key_path).await? // Ensure .await is used
+    // Gets the master key from `provider` and unseals the keyring in `key_dir`
+    // with it. The first time, the keyring starts with one generated key.
+    pub(crate) async fn open(
+        key_dir: &Path,
+        provider: Box<dyn KeyProvider>,
+        algorithm: Algorithm,
+    ) -> Result<Self, EncryptionError> {
+        // First, since a key file provider creates its file in there
+        let keyring_path = if provider.is_ephemeral() {
+            None
         } else {
-            let mut key = vec![0u8; 32];
-            OsRng.fill_bytes(&mut key);
-            tokio::fs::write(key_path, &key).await?; // Ensure .await is used
-            key
+            create_key_dir(key_dir).await?;
+            Some(key_dir.join(KEYRING_FILE))
+        };
+        let master_key = provider.master_key().await?;
+        let master = MasterCipher::new(master_key.key.clone())?;
+
+        let existing = match &keyring_path {
+            Some(path) => match check_key_file(path).await {
+                Ok(()) => Some(Keyring::unseal(&master.cipher(), &fs::read(path).await?)?),
+                Err(EncryptionError::IoError(e)) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            },
+            None => None,
+        };
+        let keyring = match existing {
+            Some(keyring) => keyring,
+            None => {
+                let keyring = Keyring {
+                    current: 1,
+                    keys: BTreeMap::from([(1, KeyEntry::generate()?)]),
+                    tenants: BTreeMap::new(),
+                    next_tenant_id: 1,
+                };
+                if let Some(path) = &keyring_path {
+                    atomic_write::write_secret_async(path, keyring.seal(&master.cipher())?).await?;
+                }
+                keyring
+            }
+        };
+
+        Ok(EncryptionService {
+            keyring_path,
+            algorithm,
+            provider,
+            master: RwLock::new(master),
+            keyring: RwLock::new(Arc::new(keyring)),
+            changes: Mutex::new(()),
+        })
+    }
+
+    pub(crate) fn current_key_id(&self) -> u32 {
+        self.read_keyring().current
+    }
+
+    pub(crate) fn key_ids(&self) -> Vec<u32> {
+        self.read_keyring().keys.keys().copied().collect()
+    }
+
+    pub(crate) fn encrypt(&self, plaintext: &[u8], context: EntryContext<'_>) -> Result<Vec<u8>, EncryptionError> {
+        self.encrypt_under(None, plaintext, context)
+    }
+
+    // Encrypts a value belonging to `tenant`, creating the tenant's key on its
+    // first write.
+    pub(crate) async fn encrypt_for_tenant(
+        &self,
+        tenant: &str,
+        plaintext: &[u8],
+        context: EntryContext<'_>,
+    ) -> Result<Vec<u8>, EncryptionError> {
+        let key_id = self.tenant_key_id(tenant).await?;
+        self.encrypt_under(Some(key_id), plaintext, context)
+    }
+
+    // `key_id` None wraps the data key with the current keyring key.
+    fn encrypt_under(
+        &self,
+        key_id: Option<u32>,
+        plaintext: &[u8],
+        context: EntryContext<'_>,
+    ) -> Result<Vec<u8>, EncryptionError> {
+        let algorithm = self.algorithm;
+        let mut data_key = Zeroizing::new([0u8; KEY_LEN]);
+        OsRng.fill_bytes(&mut data_key[..]);
+        let key = &data_key[..];
+        let aad = context.aad(algorithm);
+        let (nonce, ciphertext) = match algorithm {
+            Algorithm::Aes256Gcm => seal_value::<Aes256Gcm>(key, plaintext, &aad)?,
+            Algorithm::Aes256GcmSiv => seal_value::<Aes256GcmSiv>(key, plaintext, &aad)?,
+            Algorithm::ChaCha20Poly1305 => seal_value::<ChaCha20Poly1305>(key, plaintext, &aad)?,
+            Algorithm::XChaCha20Poly1305 => seal_value::<XChaCha20Poly1305>(key, plaintext, &aad)?,
         };
-        let cipher = Aes256Gcm::new_from_slice(&key)?;
-        Ok(EncryptionService { cipher })
+
+        let keyring = self.read_keyring();
+        let key_id = key_id.unwrap_or(keyring.current);
+        let wrapped_key = seal(&keyring.wrapping_key(key_id)?.cipher(), key, &[])?;
+        Ok(Envelope {
+            algorithm,
+            key_id,
+            wrapped_key: &wrapped_key,
+            nonce: &nonce,
+            ciphertext: &ciphertext,
+        }
+        .encode())
+    }
+
+    pub(crate) fn decrypt(&self, data: &[u8], context: EntryContext<'_>) -> Result<Vec<u8>, EncryptionError> {
+        let keyring = self.read_keyring();
+        let envelope = Envelope::parse(data)?;
+        let entry = keyring.wrapping_key(envelope.key_id)?;
+        let data_key = Zeroizing::new(open(&entry.cipher(), envelope.wrapped_key, &[])?);
+        let (key, nonce, ciphertext) = (&data_key[..], envelope.nonce, envelope.ciphertext);
+        let aad = context.aad(envelope.algorithm);
+        match envelope.algorithm {
+            Algorithm::Aes256Gcm => open_value::<Aes256Gcm>(key, nonce, ciphertext, &aad),
+            Algorithm::Aes256GcmSiv => open_value::<Aes256GcmSiv>(key, nonce, ciphertext, &aad),
+            Algorithm::ChaCha20Poly1305 => open_value::<ChaCha20Poly1305>(key, nonce, ciphertext, &aad),
+            Algorithm::XChaCha20Poly1305 => open_value::<XChaCha20Poly1305>(key, nonce, ciphertext, &aad),
+        }
+    }
+
+    // Moves a ciphertext onto the current key. Only the data key is rewrapped;
+    // the value's own ciphertext is carried over byte for byte.
+    pub(crate) fn rewrap(&self, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let envelope = Envelope::parse(data)?;
+        if is_tenant_key(envelope.key_id) {
+            return Ok(data.to_vec()); // Tenant keys are not rotated
+        }
+        let keyring = self.read_keyring();
+        let entry = keyring.wrapping_key(envelope.key_id)?;
+        let data_key = Zeroizing::new(open(&entry.cipher(), envelope.wrapped_key, &[])?);
+        let wrapped_key = seal(&keyring.keys[&keyring.current].cipher(), &data_key, &[])?;
+        Ok(Envelope {
+            key_id: keyring.current,
+            wrapped_key: &wrapped_key,
+            ..envelope
+        }
+        .encode())
+    }
+
+    // Whether a ciphertext was produced by anything but the current key or a
+    // tenant key.
+    pub(crate) fn needs_reencryption(&self, data: &[u8]) -> bool {
+        match Envelope::parse(data) {
+            Ok(envelope) => envelope.key_id != self.current_key_id() && !is_tenant_key(envelope.key_id),
+            Err(_) => true,
+        }
+    }
+
+    // The key a ciphertext depends on, if it is still in the keyring.
+    pub(crate) fn key_id_of(&self, data: &[u8]) -> Option<u32> {
+        let keyring = self.read_keyring();
+        let envelope = Envelope::parse(data).ok()?;
+        keyring.keys.contains_key(&envelope.key_id).then_some(envelope.key_id)
+    }
+
+    // Whether a ciphertext was wrapped by a tenant key that has since been shredded.
+    pub(crate) fn is_shredded(&self, data: &[u8]) -> bool {
+        match Envelope::parse(data) {
+            Ok(envelope) => matches!(
+                self.read_keyring().wrapping_key(envelope.key_id),
+                Err(EncryptionError::ShreddedTenant(_))
+            ),
+            Err(_) => false,
+        }
+    }
+
+    // Adds a fresh key and makes it current. Older keys keep decrypting.
+    pub(crate) async fn rotate(&self) -> Result<u32, EncryptionError> {
+        let _changes = self.changes.lock().await;
+        let mut keyring = Keyring::clone(&self.read_keyring());
+        let key_id = keyring.keys.keys().next_back().copied().unwrap_or(0) + 1;
+        keyring.keys.insert(key_id, KeyEntry::generate()?);
+        keyring.current = key_id;
+
+        self.persist(Arc::new(keyring), None).await?;
+        Ok(key_id)
+    }
+
+    // Destroys a key for good. Callers must have moved every ciphertext off it.
+    pub(crate) async fn retire(&self, key_id: u32) -> Result<(), EncryptionError> {
+        let _changes = self.changes.lock().await;
+        let mut keyring = Keyring::clone(&self.read_keyring());
+        if keyring.current == key_id {
+            return Err(EncryptionError::CurrentKey(key_id));
+        }
+        if keyring.keys.remove(&key_id).is_none() {
+            return Err(EncryptionError::UnknownKey(key_id));
+        }
+
+        self.persist(Arc::new(keyring), None).await?;
+        Ok(())
+    }
+
+    // The envelope key ID for `tenant`'s key, creating the key if the tenant
+    // has none yet. A shredded tenant never gets a new one.
+    pub(crate) async fn tenant_key_id(&self, tenant: &str) -> Result<u32, EncryptionError> {
+        if let Some(key_id) = self.existing_tenant_key_id(tenant)? {
+            return Ok(key_id);
+        }
+        validate_tenant(tenant)?;
+
+        let _changes = self.changes.lock().await;
+        if let Some(key_id) = self.existing_tenant_key_id(tenant)? {
+            return Ok(key_id); // Created while waiting for the lock
+        }
+        let mut keyring = Keyring::clone(&self.read_keyring());
+        let id = keyring.next_tenant_id;
+        if id & TENANT_KEY_FLAG != 0 {
+            return Err(EncryptionError::InvalidTenant("tenant IDs are exhausted".to_string()));
+        }
+        keyring.next_tenant_id += 1;
+        keyring.tenants.insert(
+            tenant.to_string(),
+            TenantKey {
+                id,
+                entry: Some(KeyEntry::generate()?),
+            },
+        );
+        self.persist(Arc::new(keyring), None).await?;
+        Ok(id | TENANT_KEY_FLAG)
+    }
+
+    fn existing_tenant_key_id(&self, tenant: &str) -> Result<Option<u32>, EncryptionError> {
+        match self.read_keyring().tenants.get(tenant) {
+            Some(TenantKey { entry: None, .. }) => Err(EncryptionError::ShreddedTenant(tenant.to_string())),
+            Some(TenantKey { id, .. }) => Ok(Some(id | TENANT_KEY_FLAG)),
+            None => Ok(None),
+        }
+    }
+
+    // The tenant's envelope key ID and whether its key has been destroyed.
+    pub(crate) fn tenant_key_state(&self, tenant: &str) -> Option<(u32, bool)> {
+        self.read_keyring()
+            .tenants
+            .get(tenant)
+            .map(|key| (key.id | TENANT_KEY_FLAG, key.entry.is_none()))
+    }
+
+    // Destroys a tenant's key and persists the keyring without it. Returns the
+    // envelope key ID its values carry. Shredding twice is not an error.
+    pub(crate) async fn shred_tenant(&self, tenant: &str) -> Result<u32, EncryptionError> {
+        let _changes = self.changes.lock().await;
+        let mut keyring = Keyring::clone(&self.read_keyring());
+        let key = keyring
+            .tenants
+            .get_mut(tenant)
+            .ok_or_else(|| EncryptionError::UnknownTenant(tenant.to_string()))?;
+        let key_id = key.id | TENANT_KEY_FLAG;
+        if key.entry.take().is_none() {
+            return Ok(key_id);
+        }
+        self.persist(Arc::new(keyring), None).await?;
+        Ok(key_id)
+    }
+
+    // Asks the provider for the master key again (subject to its caching) and
+    // returns it if it is not the one sealing the keyring.
+    pub(crate) async fn changed_master_key(&self) -> Result<Option<MasterKey>, EncryptionError> {
+        let master_key = self.provider.master_key().await?;
+        Ok((master_key.key.expose() != self.read_master().key.expose()).then_some(master_key))
+    }
+
+    // Seals the keyring under `master_key`; the keys inside stay as they are.
+    pub(crate) async fn reseal(&self, master_key: MasterKey) -> Result<(), EncryptionError> {
+        let _changes = self.changes.lock().await;
+        let keyring = self.read_keyring();
+        self.persist(keyring, Some(MasterCipher::new(master_key.key)?)).await
+    }
+
+    pub(crate) fn key_source(&self) -> String {
+        self.provider.describe()
+    }
+
+    // Writes the keyring sealed under `master`, or the current master key, and
+    // only then makes both current. Callers hold `changes`.
+    async fn persist(&self, keyring: Arc<Keyring>, master: Option<MasterCipher>) -> Result<(), EncryptionError> {
+        if let Some(path) = &self.keyring_path {
+            let sealed = match &master {
+                Some(master) => keyring.seal(&master.cipher())?,
+                None => self.seal_with_current(&keyring)?,
+            };
+            atomic_write::write_secret_async(path, sealed).await?;
+        }
+        *self.keyring.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = keyring;
+        if let Some(master) = master {
+            *self.master.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = master;
+        }
+        Ok(())
+    }
+
+    fn seal_with_current(&self, keyring: &Keyring) -> Result<Vec<u8>, EncryptionError> {
+        keyring.seal(&self.read_master().cipher())
+    }
+
+    fn read_master(&self) -> std::sync::RwLockReadGuard<'_, MasterCipher> {
+        self.master.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn read_keyring(&self) -> Arc<Keyring> {
+        self.keyring.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
     }
 }
 
+pub(crate) fn is_tenant_key(key_id: u32) -> bool {
+    key_id & TENANT_KEY_FLAG != 0
+}
+
+pub(crate) fn validate_tenant(tenant: &str) -> Result<(), EncryptionError> {
+    if tenant.is_empty() {
+        return Err(EncryptionError::InvalidTenant("names can't be empty".to_string()));
+    }
+    if tenant.len() > u16::MAX as usize {
+        return Err(EncryptionError::InvalidTenant(format!("names are limited to {} bytes", u16::MAX)));
+    }
+    if tenant.contains(TENANT_SEPARATOR) {
+        return Err(EncryptionError::InvalidTenant(format!(
+            "'{}' contains '{}'",
+            tenant,
+            TENANT_SEPARATOR
+        )));
+    }
+    Ok(())
+}
+
+// Returns the nonce and the ciphertext with its tag.
+fn seal_value<C: Aead + AeadCore + KeyInit>(
+    data_key: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), EncryptionError> {
+    let cipher = C::new_from_slice(data_key).map_err(|_| EncryptionError::EncryptFailed)?;
+    let nonce = C::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: plaintext, aad })
+        .map_err(|_| EncryptionError::EncryptFailed)?;
+    Ok((nonce.to_vec(), ciphertext))
+}
+
+// The data key has already unwrapped, so a failed tag check means the value was
+// moved or altered.
+fn open_value<C: Aead + KeyInit>(
+    data_key: &[u8],
+    nonce: &[u8],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, EncryptionError> {
+    let cipher = C::new_from_slice(data_key).map_err(|_| EncryptionError::DecryptFailed)?;
+    cipher
+        .decrypt(aead::Nonce::<C>::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| EncryptionError::ContextMismatch)
+}
 
+// Returns the nonce followed by the ciphertext and tag.
+fn seal(cipher: &Aes256Gcm, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: plaintext, aad })
+        .map_err(|_| EncryptionError::EncryptFailed)?;
+    let mut output = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    output.extend_from_slice(&nonce);
+    output.extend_from_slice(&ciphertext);
+    Ok(output)
+}
+
+// `data` is the nonce followed by the ciphertext and tag.
+fn open(cipher: &Aes256Gcm, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+    if data.len() < NONCE_LEN {
+        return Err(EncryptionError::DecryptFailed);
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| EncryptionError::DecryptFailed)
+}
@@ -1,16 +1,95 @@
+use std::{io, sync::atomic::Ordering};
+
+use tokio::fs;
+
+use crate::{backup_and_recovery::BACKUP_ARCHIVE_FILE, CacheError, DiskCache, BACKUP_DIR};
+
 impl DiskCache {
-    async fn evict_if_necessary(&self) {
-        let mut map = self.map.lock().await;
-        if map.len() > self.config.cache_size {
-            let mut entries: Vec<_> = map.iter().collect();
-            entries.sort_by_key(|&(_, entry)| (entry.access_count, entry.expiry));
-            
-            // Evict entries starting from the least accessed
-            while map.len() > self.config.cache_size {
-                if let Some((key, _)) = entries.pop() {
-                    map.remove(key);
+    // Brings both tiers back within their byte budgets. Every entry is written
+    // through to disk, so the memory tier sheds load by demoting: its copy is
+    // dropped and the next hit promotes the record back from disk. Only the disk
+    // tier's victims actually leave the cache.
+    //
+    // The disk budget covers everything the cache keeps on disk: dead records
+    // and tombstones, hint files, the write-ahead log and backups. Deleting an
+    // entry only appends a tombstone, so once over budget every segment with
+    // garbage is compacted first, and victims are then evicted until the live
+    // records fit in what the rest leaves over. Their tombstones are reclaimed
+    // by a later compaction.
+    pub(crate) async fn evict_if_necessary(&self) -> Result<(), CacheError> {
+        while self.map.memory_bytes() > self.memory_capacity_bytes {
+            match self.map.demote_next() {
+                Some(_) => self.metrics.record_demotion(),
+                None => break,
+            }
+        }
+
+        if self.disk_bytes().await <= self.disk_capacity_bytes {
+            return Ok(());
+        }
+        self.compact_segments(0.0).await?;
+
+        let live_bytes = self.store.live_bytes().await;
+        let overhead = self.disk_bytes().await.saturating_sub(live_bytes);
+        let live_budget = self.disk_capacity_bytes.saturating_sub(overhead);
+        while self.store.live_bytes().await > live_budget {
+            let victim = self.disk_eviction.victim();
+            match victim {
+                // Evicted entries must not come back from disk on the next miss
+                Some(key) => {
+                    self.delete_entry(&key).await?;
+                    self.metrics.record_eviction();
                 }
+                None => break,
             }
         }
+        Ok(())
+    }
+
+    // Everything counted against `disk_capacity_bytes`.
+    pub(crate) async fn disk_bytes(&self) -> u64 {
+        let wal_bytes = self.wal.as_ref().map_or(0, |wal| wal.bytes());
+        self.store.disk_bytes().await + wal_bytes + self.backup_bytes.load(Ordering::Relaxed)
+    }
+
+    // Re-reads the size of the backups; they only change when one is taken.
+    pub(crate) async fn measure_backups(&self) -> io::Result<()> {
+        let mut bytes = match fs::metadata(self.cache_dir.join(BACKUP_ARCHIVE_FILE)).await {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        match fs::read_dir(self.cache_dir.join(BACKUP_DIR)).await {
+            Ok(mut entries) => {
+                while let Some(entry) = entries.next_entry().await? {
+                    bytes += entry.metadata().await?.len();
+                }
+            }
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            Err(_) => {}
+        }
+        self.backup_bytes.store(bytes, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_cache;
+
+    #[tokio::test]
+    async fn the_disk_budget_counts_dead_records_and_the_log() {
+        let cache = test_cache("disk-budget", |config| config.disk_capacity_bytes = 4096).await;
+        cache.set("first", &[1; 100], None).await.unwrap();
+        // The log holds a second copy of the record
+        assert!(cache.disk_bytes().await >= 2 * cache.store.live_bytes().await);
+
+        // The live records alone stay far below the budget; their overwrites don't
+        for _ in 0..20 {
+            cache.set("churn", &[2; 100], None).await.unwrap();
+        }
+        assert!(cache.stats().await.evictions > 0);
+        assert!(cache.store.live_bytes().await < 4096);
+        std::fs::remove_dir_all(&cache.cache_dir).unwrap();
     }
 }
@@ -0,0 +1,225 @@
+// Binary envelope around every ciphertext the cache writes.
+//
+// Cache values in the segment store, the WAL and backup archives all carry the
+// same self-describing header, so a reader can tell which format, algorithm and
+// keyring key produced the bytes before attempting to decrypt them. The parser
+// validates every length against the input and rejects unknown versions and
+// algorithms outright rather than guessing.
+//
+// Layout (integers little endian):
+//   magic: b"TRCE" | version: u8 | algorithm: u8 | key_id: u32
+//   | wrapped_key_len: u16 | wrapped_key | nonce_len: u8 | nonce | ciphertext and tag
+//
+// `wrapped_key` is the per-value data key sealed by keyring key `key_id`. The
+// version and algorithm are also authenticated as associated data; the key ID
+// and wrapped key are not, since rewrapping replaces them.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+const MAGIC: &[u8; 4] = b"TRCE";
+pub(crate) const VERSION: u8 = 1;
+const TAG_LEN: usize = 16;
+// magic | version | algorithm | key_id | wrapped_key_len
+const FIXED_HEADER_LEN: usize = 4 + 1 + 1 + 4 + 2;
+
+// IDs are part of the on-disk format and must never be reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub(crate) enum Algorithm {
+    #[default]
+    #[serde(rename = "aes_256_gcm")]
+    Aes256Gcm,
+    #[serde(rename = "aes_256_gcm_siv")]
+    Aes256GcmSiv,
+    #[serde(rename = "chacha20_poly1305")]
+    ChaCha20Poly1305,
+    #[serde(rename = "xchacha20_poly1305")]
+    XChaCha20Poly1305,
+}
+
+impl Algorithm {
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Algorithm::Aes256Gcm => 1,
+            Algorithm::Aes256GcmSiv => 2,
+            Algorithm::ChaCha20Poly1305 => 3,
+            Algorithm::XChaCha20Poly1305 => 4,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Algorithm::Aes256Gcm),
+            2 => Some(Algorithm::Aes256GcmSiv),
+            3 => Some(Algorithm::ChaCha20Poly1305),
+            4 => Some(Algorithm::XChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn nonce_len(self) -> usize {
+        match self {
+            Algorithm::Aes256Gcm | Algorithm::Aes256GcmSiv | Algorithm::ChaCha20Poly1305 => 12,
+            Algorithm::XChaCha20Poly1305 => 24,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum EnvelopeError {
+    Truncated,
+    BadMagic,
+    UnknownVersion(u8),
+    UnknownAlgorithm(u8),
+    BadNonceLength { expected: usize, actual: usize },
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvelopeError::Truncated => write!(f, "ciphertext envelope is truncated"),
+            EnvelopeError::BadMagic => write!(f, "not a ciphertext envelope"),
+            EnvelopeError::UnknownVersion(version) => write!(f, "unknown envelope version {}", version),
+            EnvelopeError::UnknownAlgorithm(id) => write!(f, "unknown encryption algorithm {}", id),
+            EnvelopeError::BadNonceLength { expected, actual } => {
+                write!(f, "nonce is {} bytes, expected {}", actual, expected)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Envelope<'a> {
+    pub(crate) algorithm: Algorithm,
+    pub(crate) key_id: u32,
+    pub(crate) wrapped_key: &'a [u8],
+    pub(crate) nonce: &'a [u8],
+    pub(crate) ciphertext: &'a [u8], // Includes the tag
+}
+
+impl<'a> Envelope<'a> {
+    pub(crate) fn parse(data: &'a [u8]) -> Result<Self, EnvelopeError> {
+        if data.len() < MAGIC.len() {
+            return Err(EnvelopeError::Truncated);
+        }
+        if &data[..MAGIC.len()] != MAGIC {
+            return Err(EnvelopeError::BadMagic);
+        }
+        let header = data.get(..FIXED_HEADER_LEN).ok_or(EnvelopeError::Truncated)?;
+        if header[4] != VERSION {
+            return Err(EnvelopeError::UnknownVersion(header[4]));
+        }
+        let algorithm = Algorithm::from_id(header[5]).ok_or(EnvelopeError::UnknownAlgorithm(header[5]))?;
+        let key_id = u32::from_le_bytes(header[6..10].try_into().unwrap());
+        let wrapped_key_len = u16::from_le_bytes(header[10..12].try_into().unwrap()) as usize;
+
+        let mut cursor = FIXED_HEADER_LEN;
+        let wrapped_key = take(data, &mut cursor, wrapped_key_len)?;
+        let nonce_len = take(data, &mut cursor, 1)?[0] as usize;
+        if nonce_len != algorithm.nonce_len() {
+            return Err(EnvelopeError::BadNonceLength {
+                expected: algorithm.nonce_len(),
+                actual: nonce_len,
+            });
+        }
+        let nonce = take(data, &mut cursor, nonce_len)?;
+        let ciphertext = &data[cursor..];
+        if ciphertext.len() < TAG_LEN {
+            return Err(EnvelopeError::Truncated);
+        }
+
+        Ok(Envelope {
+            algorithm,
+            key_id,
+            wrapped_key,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut output =
+            Vec::with_capacity(FIXED_HEADER_LEN + self.wrapped_key.len() + 1 + self.nonce.len() + self.ciphertext.len());
+        output.extend_from_slice(MAGIC);
+        output.push(VERSION);
+        output.push(self.algorithm.id());
+        output.extend_from_slice(&self.key_id.to_le_bytes());
+        output.extend_from_slice(&(self.wrapped_key.len() as u16).to_le_bytes());
+        output.extend_from_slice(self.wrapped_key);
+        output.push(self.nonce.len() as u8);
+        output.extend_from_slice(self.nonce);
+        output.extend_from_slice(self.ciphertext);
+        output
+    }
+}
+
+fn take<'a>(data: &'a [u8], cursor: &mut usize, len: usize) -> Result<&'a [u8], EnvelopeError> {
+    let slice = data.get(*cursor..*cursor + len).ok_or(EnvelopeError::Truncated)?;
+    *cursor += len;
+    Ok(slice)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Vec<u8> {
+        Envelope {
+            algorithm: Algorithm::XChaCha20Poly1305,
+            key_id: 7,
+            wrapped_key: &[0xAA; 48],
+            nonce: &[0x11; 24],
+            ciphertext: &[0x22; 40],
+        }
+        .encode()
+    }
+
+    #[test]
+    fn round_trips() {
+        let encoded = sample();
+        let envelope = Envelope::parse(&encoded).unwrap();
+        assert_eq!(envelope.algorithm, Algorithm::XChaCha20Poly1305);
+        assert_eq!(envelope.key_id, 7);
+        assert_eq!(envelope.wrapped_key, &[0xAA; 48]);
+        assert_eq!(envelope.nonce, &[0x11; 24]);
+        assert_eq!(envelope.ciphertext, &[0x22; 40]);
+        assert_eq!(envelope.encode(), encoded);
+    }
+
+    #[test]
+    fn rejects_every_truncation() {
+        let encoded = sample();
+        // Anything shorter than the full header plus a tag
+        let min_len = encoded.len() - 40 + TAG_LEN;
+        for len in 0..min_len {
+            assert_eq!(Envelope::parse(&encoded[..len]).unwrap_err(), EnvelopeError::Truncated, "length {}", len);
+        }
+        assert!(Envelope::parse(&encoded[..min_len]).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_version_and_algorithm() {
+        let mut encoded = sample();
+        encoded[4] = VERSION + 1;
+        assert_eq!(Envelope::parse(&encoded).unwrap_err(), EnvelopeError::UnknownVersion(VERSION + 1));
+
+        let mut encoded = sample();
+        encoded[5] = 0xFF;
+        assert_eq!(Envelope::parse(&encoded).unwrap_err(), EnvelopeError::UnknownAlgorithm(0xFF));
+    }
+
+    #[test]
+    fn rejects_bad_magic_and_nonce_length() {
+        let mut encoded = sample();
+        encoded[0] = b'X';
+        assert_eq!(Envelope::parse(&encoded).unwrap_err(), EnvelopeError::BadMagic);
+
+        let mut encoded = sample();
+        encoded[FIXED_HEADER_LEN + 48] = 12;
+        assert_eq!(
+            Envelope::parse(&encoded).unwrap_err(),
+            EnvelopeError::BadNonceLength { expected: 24, actual: 12 }
+        );
+    }
+}
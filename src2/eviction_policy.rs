@@ -0,0 +1,821 @@
+// Eviction policies for DiskCache.
+//
+// Policies only track keys; the cache owns the entries and asks `victim` for a
+// key to drop whenever it is over budget. Every operation is O(1) amortised:
+// recency lists are intrusive doubly linked lists over a slab, and LFU keeps its
+// frequency buckets in a linked list ordered by count.
+//
+// A policy shared by every request, like the disk tier's, sits behind a
+// `BufferedPolicy`: reads only append the key to one of several striped
+// buffers, which are replayed into the policy in batches, so hits never wait
+// on the policy lock. Recency is a hint, so a batch that finds the policy busy
+// waits for the next one, and is dropped if the stripe keeps growing.
+
+use std::collections::{hash_map::DefaultHasher, HashMap};
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::{Mutex, MutexGuard, TryLockError};
+
+use crate::storage_management::CacheEvictionPolicy;
+
+pub(crate) trait EvictionPolicy: Send {
+    // Called on every write; a key that is already tracked counts as an access.
+    fn on_insert(&mut self, key: &str);
+    fn on_access(&mut self, key: &str);
+    fn on_remove(&mut self, key: &str);
+    // Picks the next key to evict and stops tracking it.
+    fn victim(&mut self) -> Option<String>;
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn len(&self) -> usize;
+}
+
+pub(crate) fn new_policy(kind: CacheEvictionPolicy, capacity: usize) -> Box<dyn EvictionPolicy> {
+    let capacity = capacity.max(1);
+    match kind {
+        CacheEvictionPolicy::LRU => Box::new(Lru::default()),
+        CacheEvictionPolicy::LFU => Box::new(Lfu::default()),
+        CacheEvictionPolicy::ARC => Box::new(AdaptiveReplacement::new(capacity)),
+        CacheEvictionPolicy::TwoQ => Box::new(TwoQueue::new(capacity)),
+        CacheEvictionPolicy::WTinyLFU => Box::new(WTinyLfu::new(capacity)),
+    }
+}
+
+const ACCESS_BATCH: usize = 64;
+const MAX_PENDING_ACCESSES: usize = 16 * ACCESS_BATCH; // Per stripe
+
+pub(crate) struct BufferedPolicy {
+    policy: Mutex<Box<dyn EvictionPolicy>>,
+    stripes: Vec<Mutex<Vec<String>>>, // Accesses not yet seen by the policy
+}
+
+impl BufferedPolicy {
+    pub(crate) fn new(policy: Box<dyn EvictionPolicy>, stripe_count: usize) -> Self {
+        BufferedPolicy {
+            policy: Mutex::new(policy),
+            stripes: (0..stripe_count.max(1)).map(|_| Mutex::new(Vec::new())).collect(),
+        }
+    }
+
+    pub(crate) fn record_access(&self, key: &str) {
+        let mut stripe = self.stripes[stripe_index(key, self.stripes.len())]
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        stripe.push(key.to_string());
+        if stripe.len() < ACCESS_BATCH {
+            return;
+        }
+        match self.policy.try_lock() {
+            Ok(mut policy) => replay(&mut policy, &mut stripe),
+            Err(TryLockError::Poisoned(poisoned)) => replay(&mut poisoned.into_inner(), &mut stripe),
+            Err(TryLockError::WouldBlock) if stripe.len() >= MAX_PENDING_ACCESSES => stripe.clear(),
+            Err(TryLockError::WouldBlock) => {}
+        }
+    }
+
+    // Direct access for inserts and removals. Buffered accesses to a key that
+    // is removed before they are replayed are ignored by every policy.
+    pub(crate) fn lock(&self) -> MutexGuard<'_, Box<dyn EvictionPolicy>> {
+        self.policy.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Replays every buffered access first, so the victim reflects recent reads.
+    pub(crate) fn victim(&self) -> Option<String> {
+        let mut policy = self.lock();
+        for stripe in &self.stripes {
+            replay(&mut policy, &mut stripe.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+        }
+        policy.victim()
+    }
+
+    pub(crate) fn replace(&self, policy: Box<dyn EvictionPolicy>) {
+        let mut current = self.lock();
+        for stripe in &self.stripes {
+            stripe.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clear();
+        }
+        *current = policy;
+    }
+}
+
+fn replay(policy: &mut Box<dyn EvictionPolicy>, accesses: &mut Vec<String>) {
+    for key in mem::take(accesses) {
+        policy.on_access(&key);
+    }
+}
+
+fn stripe_index(key: &str, stripe_count: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % stripe_count
+}
+
+struct Node {
+    key: String,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+// Recency-ordered key list: front is most recent, back is the eviction end.
+#[derive(Default)]
+struct KeyList {
+    nodes: Vec<Option<Node>>,
+    free: Vec<usize>,
+    slots: HashMap<String, usize>,
+    head: Option<usize>,
+    tail: Option<usize>,
+}
+
+impl KeyList {
+    fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.slots.contains_key(key)
+    }
+
+    fn back(&self) -> Option<&str> {
+        self.tail.and_then(|slot| self.nodes[slot].as_ref()).map(|node| node.key.as_str())
+    }
+
+    fn push_front(&mut self, key: &str) {
+        if self.move_to_front(key) {
+            return;
+        }
+        let node = Node {
+            key: key.to_string(),
+            prev: None,
+            next: self.head,
+        };
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.nodes[slot] = Some(node);
+                slot
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        };
+        if let Some(head) = self.head {
+            self.node_mut(head).prev = Some(slot);
+        }
+        self.head = Some(slot);
+        if self.tail.is_none() {
+            self.tail = Some(slot);
+        }
+        self.slots.insert(key.to_string(), slot);
+    }
+
+    fn move_to_front(&mut self, key: &str) -> bool {
+        let slot = match self.slots.get(key) {
+            Some(slot) => *slot,
+            None => return false,
+        };
+        if self.head != Some(slot) {
+            self.unlink(slot);
+            let head = self.head;
+            {
+                let node = self.node_mut(slot);
+                node.prev = None;
+                node.next = head;
+            }
+            if let Some(head) = head {
+                self.node_mut(head).prev = Some(slot);
+            }
+            self.head = Some(slot);
+            if self.tail.is_none() {
+                self.tail = Some(slot);
+            }
+        }
+        true
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        match self.slots.remove(key) {
+            Some(slot) => {
+                self.unlink(slot);
+                self.nodes[slot] = None;
+                self.free.push(slot);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn pop_back(&mut self) -> Option<String> {
+        let key = self.back()?.to_string();
+        self.remove(&key);
+        Some(key)
+    }
+
+    fn unlink(&mut self, slot: usize) {
+        let (prev, next) = {
+            let node = self.node_mut(slot);
+            (node.prev, node.next)
+        };
+        match prev {
+            Some(prev) => self.node_mut(prev).next = next,
+            None => self.head = next,
+        }
+        match next {
+            Some(next) => self.node_mut(next).prev = prev,
+            None => self.tail = prev,
+        }
+    }
+
+    fn node_mut(&mut self, slot: usize) -> &mut Node {
+        self.nodes[slot].as_mut().expect("linked slot is occupied")
+    }
+}
+
+#[derive(Default)]
+struct Lru {
+    keys: KeyList,
+}
+
+impl EvictionPolicy for Lru {
+    fn on_insert(&mut self, key: &str) {
+        self.keys.push_front(key);
+    }
+
+    fn on_access(&mut self, key: &str) {
+        self.keys.move_to_front(key);
+    }
+
+    fn on_remove(&mut self, key: &str) {
+        self.keys.remove(key);
+    }
+
+    fn victim(&mut self) -> Option<String> {
+        self.keys.pop_back()
+    }
+
+    fn len(&self) -> usize {
+        self.keys.len()
+    }
+}
+
+struct FrequencyBucket {
+    keys: KeyList,
+    prev: Option<u64>,
+    next: Option<u64>,
+}
+
+// Constant-time LFU: buckets per access count, linked in ascending order, with
+// LRU order inside each bucket to break ties.
+#[derive(Default)]
+struct Lfu {
+    counts: HashMap<String, u64>,
+    buckets: HashMap<u64, FrequencyBucket>,
+    lowest: Option<u64>,
+}
+
+impl Lfu {
+    fn unlink_if_empty(&mut self, count: u64) {
+        let empty = self.buckets.get(&count).is_some_and(|bucket| bucket.keys.is_empty());
+        if !empty {
+            return;
+        }
+        let bucket = self.buckets.remove(&count).unwrap();
+        match bucket.prev {
+            Some(prev) => self.buckets.get_mut(&prev).unwrap().next = bucket.next,
+            None => self.lowest = bucket.next,
+        }
+        if let Some(next) = bucket.next {
+            self.buckets.get_mut(&next).unwrap().prev = bucket.prev;
+        }
+    }
+}
+
+impl EvictionPolicy for Lfu {
+    fn on_insert(&mut self, key: &str) {
+        if self.counts.contains_key(key) {
+            self.on_access(key);
+            return;
+        }
+        if !self.buckets.contains_key(&1) {
+            // 1 is the smallest possible count, so the new bucket always becomes the head
+            let next = self.lowest;
+            if let Some(next) = next {
+                self.buckets.get_mut(&next).unwrap().prev = Some(1);
+            }
+            self.buckets.insert(
+                1,
+                FrequencyBucket {
+                    keys: KeyList::default(),
+                    prev: None,
+                    next,
+                },
+            );
+            self.lowest = Some(1);
+        }
+        self.buckets.get_mut(&1).unwrap().keys.push_front(key);
+        self.counts.insert(key.to_string(), 1);
+    }
+
+    fn on_access(&mut self, key: &str) {
+        let count = match self.counts.get(key) {
+            Some(count) => *count,
+            None => return,
+        };
+        let target = count + 1;
+        if !self.buckets.contains_key(&target) {
+            let next = self.buckets[&count].next;
+            if let Some(next) = next {
+                self.buckets.get_mut(&next).unwrap().prev = Some(target);
+            }
+            self.buckets.get_mut(&count).unwrap().next = Some(target);
+            self.buckets.insert(
+                target,
+                FrequencyBucket {
+                    keys: KeyList::default(),
+                    prev: Some(count),
+                    next,
+                },
+            );
+        }
+        self.buckets.get_mut(&count).unwrap().keys.remove(key);
+        self.buckets.get_mut(&target).unwrap().keys.push_front(key);
+        self.counts.insert(key.to_string(), target);
+        self.unlink_if_empty(count);
+    }
+
+    fn on_remove(&mut self, key: &str) {
+        if let Some(count) = self.counts.remove(key) {
+            self.buckets.get_mut(&count).unwrap().keys.remove(key);
+            self.unlink_if_empty(count);
+        }
+    }
+
+    fn victim(&mut self) -> Option<String> {
+        let lowest = self.lowest?;
+        let key = self.buckets.get_mut(&lowest).unwrap().keys.pop_back()?;
+        self.counts.remove(&key);
+        self.unlink_if_empty(lowest);
+        Some(key)
+    }
+
+    fn len(&self) -> usize {
+        self.counts.len()
+    }
+}
+
+// Adaptive Replacement Cache (Megiddo & Modha). T1/T2 hold resident keys seen
+// once/repeatedly, B1/B2 remember recently evicted keys so the split `p`
+// between recency and frequency adapts to the workload.
+struct AdaptiveReplacement {
+    capacity: usize,
+    p: usize,
+    t1: KeyList,
+    t2: KeyList,
+    b1: KeyList,
+    b2: KeyList,
+    last_hit_b2: bool,
+}
+
+impl AdaptiveReplacement {
+    fn new(capacity: usize) -> Self {
+        AdaptiveReplacement {
+            capacity,
+            p: 0,
+            t1: KeyList::default(),
+            t2: KeyList::default(),
+            b1: KeyList::default(),
+            b2: KeyList::default(),
+            last_hit_b2: false,
+        }
+    }
+
+    fn trim_ghosts(&mut self) {
+        while self.t1.len() + self.b1.len() > self.capacity && self.b1.pop_back().is_some() {}
+        while self.t1.len() + self.t2.len() + self.b1.len() + self.b2.len() > 2 * self.capacity
+            && self.b2.pop_back().is_some()
+        {}
+    }
+}
+
+impl EvictionPolicy for AdaptiveReplacement {
+    fn on_insert(&mut self, key: &str) {
+        if self.t1.contains(key) || self.t2.contains(key) {
+            self.on_access(key);
+            return;
+        }
+        self.last_hit_b2 = false;
+        if self.b1.remove(key) {
+            let delta = (self.b2.len() / self.b1.len().max(1)).max(1);
+            self.p = (self.p + delta).min(self.capacity);
+            self.t2.push_front(key);
+        } else if self.b2.remove(key) {
+            let delta = (self.b1.len() / self.b2.len().max(1)).max(1);
+            self.p = self.p.saturating_sub(delta);
+            self.last_hit_b2 = true;
+            self.t2.push_front(key);
+        } else {
+            self.t1.push_front(key);
+        }
+        self.trim_ghosts();
+    }
+
+    fn on_access(&mut self, key: &str) {
+        if self.t1.remove(key) {
+            self.t2.push_front(key);
+        } else {
+            self.t2.move_to_front(key);
+        }
+    }
+
+    fn on_remove(&mut self, key: &str) {
+        self.t1.remove(key);
+        self.t2.remove(key);
+        self.b1.remove(key);
+        self.b2.remove(key);
+    }
+
+    fn victim(&mut self) -> Option<String> {
+        let from_t1 = !self.t1.is_empty()
+            && (self.t1.len() > self.p || (self.last_hit_b2 && self.t1.len() == self.p) || self.t2.is_empty());
+        let key = if from_t1 {
+            let key = self.t1.pop_back()?;
+            self.b1.push_front(&key);
+            key
+        } else {
+            let key = self.t2.pop_back()?;
+            self.b2.push_front(&key);
+            key
+        };
+        self.trim_ghosts();
+        Some(key)
+    }
+
+    fn len(&self) -> usize {
+        self.t1.len() + self.t2.len()
+    }
+}
+
+// Full 2Q (Johnson & Shasha): first-time keys go through the A1in FIFO, keys
+// re-referenced after leaving it (remembered in the A1out ghost queue) are
+// promoted into the Am LRU.
+struct TwoQueue {
+    in_capacity: usize,
+    out_capacity: usize,
+    a1_in: KeyList,
+    a1_out: KeyList,
+    am: KeyList,
+}
+
+impl TwoQueue {
+    fn new(capacity: usize) -> Self {
+        TwoQueue {
+            in_capacity: (capacity / 4).max(1),
+            out_capacity: (capacity / 2).max(1),
+            a1_in: KeyList::default(),
+            a1_out: KeyList::default(),
+            am: KeyList::default(),
+        }
+    }
+}
+
+impl EvictionPolicy for TwoQueue {
+    fn on_insert(&mut self, key: &str) {
+        if self.a1_in.contains(key) || self.am.contains(key) {
+            self.on_access(key);
+            return;
+        }
+        if self.a1_out.remove(key) {
+            self.am.push_front(key);
+        } else {
+            self.a1_in.push_front(key);
+        }
+    }
+
+    fn on_access(&mut self, key: &str) {
+        // A1in is a FIFO on purpose: correlated re-reads must not promote a key
+        self.am.move_to_front(key);
+    }
+
+    fn on_remove(&mut self, key: &str) {
+        self.a1_in.remove(key);
+        self.a1_out.remove(key);
+        self.am.remove(key);
+    }
+
+    fn victim(&mut self) -> Option<String> {
+        if self.a1_in.len() > self.in_capacity || self.am.is_empty() {
+            let key = self.a1_in.pop_back()?;
+            self.a1_out.push_front(&key);
+            while self.a1_out.len() > self.out_capacity {
+                self.a1_out.pop_back();
+            }
+            Some(key)
+        } else {
+            self.am.pop_back()
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.a1_in.len() + self.am.len()
+    }
+}
+
+// Count-min sketch with 4-bit style saturating counters that are halved once
+// enough samples have been recorded, so old popularity fades out.
+struct FrequencySketch {
+    rows: [Vec<u8>; 4],
+    mask: usize,
+    additions: usize,
+    sample_size: usize,
+}
+
+impl FrequencySketch {
+    fn new(capacity: usize) -> Self {
+        let width = capacity.next_power_of_two().max(16);
+        FrequencySketch {
+            rows: [vec![0; width], vec![0; width], vec![0; width], vec![0; width]],
+            mask: width - 1,
+            additions: 0,
+            sample_size: capacity.saturating_mul(10).max(16),
+        }
+    }
+
+    fn slots(&self, key: &str) -> [usize; 4] {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let mut slots = [0; 4];
+        for (row, slot) in slots.iter_mut().enumerate() {
+            *slot = (hash.rotate_left(row as u32 * 16) as usize ^ row.wrapping_mul(0x9E37_79B9)) & self.mask;
+        }
+        slots
+    }
+
+    fn increment(&mut self, key: &str) {
+        let slots = self.slots(key);
+        for (row, slot) in slots.iter().enumerate() {
+            let counter = &mut self.rows[row][*slot];
+            *counter = (*counter + 1).min(15);
+        }
+        self.additions += 1;
+        if self.additions >= self.sample_size {
+            for row in self.rows.iter_mut() {
+                row.iter_mut().for_each(|counter| *counter /= 2);
+            }
+            self.additions /= 2;
+        }
+    }
+
+    fn frequency(&self, key: &str) -> u8 {
+        let slots = self.slots(key);
+        slots.iter().enumerate().map(|(row, slot)| self.rows[row][*slot]).min().unwrap_or(0)
+    }
+}
+
+// W-TinyLFU (Einziger et al.): a small LRU window absorbs bursts, and keys
+// leaving it must beat the main segmented LRU's victim on sketch frequency
+// to be admitted.
+struct WTinyLfu {
+    window_capacity: usize,
+    protected_capacity: usize,
+    window: KeyList,
+    probation: KeyList,
+    protected: KeyList,
+    // Probation entries admitted from the window but not yet judged, oldest at
+    // the back. Keys leave as soon as they are accessed or removed.
+    candidates: KeyList,
+    sketch: FrequencySketch,
+}
+
+impl WTinyLfu {
+    fn new(capacity: usize) -> Self {
+        let window_capacity = (capacity / 100).max(1);
+        let main_capacity = capacity.saturating_sub(window_capacity).max(1);
+        WTinyLfu {
+            window_capacity,
+            protected_capacity: (main_capacity * 4 / 5).max(1),
+            window: KeyList::default(),
+            probation: KeyList::default(),
+            protected: KeyList::default(),
+            candidates: KeyList::default(),
+            sketch: FrequencySketch::new(capacity),
+        }
+    }
+}
+
+impl EvictionPolicy for WTinyLfu {
+    fn on_insert(&mut self, key: &str) {
+        if self.window.contains(key) || self.probation.contains(key) || self.protected.contains(key) {
+            self.on_access(key);
+            return;
+        }
+        self.sketch.increment(key);
+        self.window.push_front(key);
+        while self.window.len() > self.window_capacity {
+            let candidate = self.window.pop_back().unwrap();
+            self.probation.push_front(&candidate);
+            self.candidates.push_front(&candidate);
+        }
+    }
+
+    fn on_access(&mut self, key: &str) {
+        self.sketch.increment(key);
+        if self.window.move_to_front(key) || self.protected.move_to_front(key) {
+            return;
+        }
+        if self.probation.remove(key) {
+            self.candidates.remove(key);
+            self.protected.push_front(key);
+            while self.protected.len() > self.protected_capacity {
+                let demoted = self.protected.pop_back().unwrap();
+                self.probation.push_front(&demoted);
+            }
+        }
+    }
+
+    fn on_remove(&mut self, key: &str) {
+        self.window.remove(key);
+        self.probation.remove(key);
+        self.protected.remove(key);
+        self.candidates.remove(key);
+    }
+
+    fn victim(&mut self) -> Option<String> {
+        if let Some(candidate) = self.candidates.pop_back() {
+            let victim = self.probation.back().map(str::to_string);
+            let evicted = match victim {
+                Some(victim) if victim != candidate => {
+                    // Admission: the window candidate only stays if it is more popular
+                    if self.sketch.frequency(&candidate) > self.sketch.frequency(&victim) {
+                        self.candidates.remove(&victim);
+                        victim
+                    } else {
+                        candidate
+                    }
+                }
+                _ => candidate,
+            };
+            self.probation.remove(&evicted);
+            return Some(evicted);
+        }
+        if let Some(key) = self.probation.pop_back() {
+            return Some(key);
+        }
+        self.protected.pop_back().or_else(|| self.window.pop_back())
+    }
+
+    fn len(&self) -> usize {
+        self.window.len() + self.probation.len() + self.protected.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn victims(policy: &mut dyn EvictionPolicy) -> Vec<String> {
+        std::iter::from_fn(|| policy.victim()).collect()
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used() {
+        let mut policy = new_policy(CacheEvictionPolicy::LRU, 8);
+        for key in ["a", "b", "c"] {
+            policy.on_insert(key);
+        }
+        policy.on_access("a");
+        assert_eq!(policy.len(), 3);
+        assert_eq!(victims(policy.as_mut()), ["b", "c", "a"]);
+        assert_eq!(policy.len(), 0);
+    }
+
+    #[test]
+    fn lfu_evicts_the_least_frequently_used_then_the_oldest() {
+        let mut policy = new_policy(CacheEvictionPolicy::LFU, 8);
+        for key in ["a", "b", "c", "d"] {
+            policy.on_insert(key);
+        }
+        policy.on_access("a");
+        policy.on_access("a");
+        policy.on_access("c");
+        assert_eq!(victims(policy.as_mut()), ["b", "d", "c", "a"]);
+    }
+
+    #[test]
+    fn lfu_remove_unlinks_emptied_buckets() {
+        let mut policy = Lfu::default();
+        policy.on_insert("a");
+        policy.on_insert("b");
+        policy.on_access("b");
+        policy.on_remove("a");
+        assert!(!policy.buckets.contains_key(&1));
+        assert_eq!(policy.lowest, Some(2));
+        assert_eq!(victims(&mut policy), ["b"]);
+        assert!(policy.buckets.is_empty());
+    }
+
+    #[test]
+    fn arc_evicts_recency_before_frequency() {
+        let mut policy = AdaptiveReplacement::new(4);
+        for key in ["a", "b", "c"] {
+            policy.on_insert(key);
+        }
+        policy.on_access("b");
+        // With p at 0, T1 (seen once) is drained before T2
+        assert_eq!(victims(&mut policy), ["a", "c", "b"]);
+        assert!(policy.b1.contains("a") && policy.b1.contains("c") && policy.b2.contains("b"));
+    }
+
+    #[test]
+    fn arc_adapts_its_target_on_ghost_hits() {
+        let mut policy = AdaptiveReplacement::new(2);
+        policy.on_insert("a");
+        policy.on_insert("b");
+        assert_eq!(policy.victim().as_deref(), Some("a"));
+
+        // A hit in B1 means T1 was too small
+        policy.on_insert("a");
+        assert_eq!(policy.p, 1);
+        assert!(policy.t2.contains("a") && !policy.b1.contains("a"));
+
+        // A hit in B2 means T2 was
+        assert_eq!(policy.victim().as_deref(), Some("a"));
+        assert!(policy.b2.contains("a"));
+        policy.on_insert("a");
+        assert_eq!(policy.p, 0);
+        assert!(policy.t2.contains("a") && !policy.b2.contains("a"));
+    }
+
+    #[test]
+    fn arc_remove_forgets_ghosts() {
+        let mut policy = AdaptiveReplacement::new(2);
+        policy.on_insert("a");
+        policy.on_insert("b");
+        assert_eq!(policy.victim().as_deref(), Some("a"));
+        policy.on_remove("a");
+        assert!(!policy.b1.contains("a"));
+
+        // Back as a new key, not a ghost hit
+        policy.on_insert("a");
+        assert_eq!(policy.p, 0);
+        assert!(policy.t1.contains("a"));
+    }
+
+    #[test]
+    fn two_queue_keeps_first_timers_in_a_fifo() {
+        let mut policy = TwoQueue::new(4);
+        for key in ["a", "b", "c"] {
+            policy.on_insert(key);
+        }
+        // Re-reads while in A1in don't promote
+        policy.on_access("a");
+        assert_eq!(policy.victim().as_deref(), Some("a"));
+        assert!(policy.a1_out.contains("a"));
+
+        // Re-referenced after leaving A1in: promoted to Am
+        policy.on_insert("a");
+        assert!(policy.am.contains("a"));
+        // A1in is drained down to its share first, then Am
+        assert_eq!(victims(&mut policy), ["b", "a", "c"]);
+    }
+
+    #[test]
+    fn two_queue_remove_forgets_ghosts() {
+        let mut policy = TwoQueue::new(4);
+        policy.on_insert("a");
+        policy.on_insert("b");
+        assert_eq!(policy.victim().as_deref(), Some("a"));
+        policy.on_remove("a");
+        assert!(!policy.a1_out.contains("a"));
+        policy.on_insert("a");
+        assert!(policy.a1_in.contains("a") && !policy.am.contains("a"));
+    }
+
+    #[test]
+    fn w_tiny_lfu_evicts_the_oldest_candidate() {
+        let mut policy = new_policy(CacheEvictionPolicy::WTinyLFU, 100);
+        for key in ["a", "b", "c"] {
+            policy.on_insert(key);
+        }
+        // The window holds one key; "a" and "b" wait in probation
+        assert_eq!(victims(policy.as_mut()), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn w_tiny_lfu_remove_and_access_drop_candidates() {
+        let mut policy = WTinyLfu::new(100);
+        for key in ["a", "b", "c", "d"] {
+            policy.on_insert(key);
+        }
+        assert_eq!(policy.candidates.len(), 3);
+        policy.on_remove("a");
+        policy.on_access("b"); // Promoted to protected
+        assert_eq!(policy.candidates.len(), 1);
+        assert!(policy.candidates.contains("c"));
+
+        assert_eq!(policy.victim().as_deref(), Some("c"));
+        assert!(policy.candidates.is_empty());
+        assert_eq!(policy.len(), 2);
+    }
+}
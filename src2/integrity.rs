@@ -0,0 +1,293 @@
+// Per-entry integrity checks and quarantine of entries that fail them.
+//
+// Encrypted values are already authenticated by their AEAD tag. Unencrypted
+// values are stored in a small frame carrying a CRC32C of the entry key and the
+// value, so a flipped bit, or a value copied under another key, is caught when
+// the entry is read. A value without a valid frame fails the check like any
+// other corruption, since a damaged magic must not turn the check off.
+//
+// A corrupt entry only ever fails its own reads. A verification pass (run at
+// startup when `verify_on_load` is set) reads every entry and moves the ones
+// whose bytes are damaged into the quarantine: the raw stored bytes are kept as a file in
+// `quarantine/`, the entry is listed in `quarantine/report.json`, and the key is
+// deleted so the rest of the cache stays usable. Entries of a shredded tenant
+// left behind by an interrupted shred are unreadable by design; they are
+// deleted without being kept. Failures that say nothing about the entry
+// itself, such as an unknown key ID or a missing keyring, stop the pass
+// instead, so a misconfigured key directory never empties the cache.
+//
+// A `report.json` that can't be parsed is renamed aside rather than failing
+// startup, and the list starts over empty.
+//
+// Frame layout: magic: b"TRCK" | crc32c(key | value): u32 | value
+
+use std::{
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::{atomic_write, CacheError, DiskCache};
+
+const MAGIC: &[u8; 4] = b"TRCK";
+const FRAME_HEADER_LEN: usize = 8;
+const REPORT_FILE: &str = "report.json";
+
+pub(crate) fn frame(key: &str, value: &[u8]) -> Vec<u8> {
+    let mut stored = Vec::with_capacity(FRAME_HEADER_LEN + value.len());
+    stored.extend_from_slice(MAGIC);
+    stored.extend_from_slice(&checksum(key, value).to_le_bytes());
+    stored.extend_from_slice(value);
+    stored
+}
+
+// Verifies a framed value and returns where the value sits inside it.
+pub(crate) fn unframe(key: &str, stored: &[u8]) -> Result<Range<usize>, CacheError> {
+    if !stored.starts_with(MAGIC) {
+        return Err(CacheError::IntegrityError);
+    }
+    let header = stored.get(..FRAME_HEADER_LEN).ok_or(CacheError::IntegrityError)?;
+    let expected = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if checksum(key, &stored[FRAME_HEADER_LEN..]) != expected {
+        return Err(CacheError::IntegrityError);
+    }
+    Ok(FRAME_HEADER_LEN..stored.len())
+}
+
+fn checksum(key: &str, value: &[u8]) -> u32 {
+    let crc = crc32c::crc32c(&(key.len() as u32).to_le_bytes());
+    let crc = crc32c::crc32c_append(crc, key.as_bytes());
+    crc32c::crc32c_append(crc, value)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct QuarantinedEntry {
+    pub(crate) key: String,
+    pub(crate) reason: String,
+    pub(crate) quarantined_at: i64, // Unix time in milliseconds
+    pub(crate) file: Option<String>, // Raw stored bytes, if they could still be read
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct VerificationReport {
+    pub(crate) checked: u64,
+    pub(crate) dropped: Vec<QuarantinedEntry>,
+    pub(crate) shredded: u64, // Leftovers of shredded tenants, deleted without a copy
+}
+
+pub(crate) struct Quarantine {
+    dir: PathBuf,
+    entries: Mutex<Vec<QuarantinedEntry>>,
+    set_aside: Option<PathBuf>, // Where an unparseable report from an earlier run was moved
+}
+
+impl Quarantine {
+    // Picks up the report left by earlier runs, so the list survives restarts.
+    pub(crate) async fn open(dir: &Path) -> io::Result<Self> {
+        let path = dir.join(REPORT_FILE);
+        let (entries, set_aside) = match fs::read(&path).await {
+            Ok(contents) => match serde_json::from_slice(&contents) {
+                Ok(entries) => (entries, None),
+                Err(_) => {
+                    let aside = dir.join(format!("{}.{}.bad", REPORT_FILE, Utc::now().timestamp_millis()));
+                    fs::rename(&path, &aside).await?;
+                    (Vec::new(), Some(aside))
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (Vec::new(), None),
+            Err(e) => return Err(e),
+        };
+        Ok(Quarantine {
+            dir: dir.to_path_buf(),
+            entries: Mutex::new(entries),
+            set_aside,
+        })
+    }
+
+    pub(crate) fn entries(&self) -> Vec<QuarantinedEntry> {
+        self.lock().clone()
+    }
+
+    pub(crate) fn set_aside_report(&self) -> Option<&Path> {
+        self.set_aside.as_deref()
+    }
+
+    async fn add(&self, key: &str, reason: String, raw: Option<Vec<u8>>) -> io::Result<QuarantinedEntry> {
+        fs::create_dir_all(&self.dir).await?;
+        let quarantined_at = Utc::now().timestamp_millis();
+        let file = match raw {
+            Some(raw) => {
+                let name = format!("{}-{}.bin", quarantined_at, self.lock().len());
+                atomic_write::write_async(&self.dir.join(&name), raw).await?;
+                Some(name)
+            }
+            None => None,
+        };
+        let entry = QuarantinedEntry {
+            key: key.to_string(),
+            reason,
+            quarantined_at,
+            file,
+        };
+
+        let report = {
+            let mut entries = self.lock();
+            entries.push(entry.clone());
+            serde_json::to_vec_pretty(&*entries).map_err(io::Error::other)?
+        };
+        atomic_write::write_async(&self.dir.join(REPORT_FILE), report).await?;
+        Ok(entry)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<QuarantinedEntry>> {
+        self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl DiskCache {
+    // Reads every entry and quarantines the ones that fail their checksum or
+    // tag, and deletes the ones whose tenant was shredded; any other error
+    // aborts the pass. Writers wait while the pass runs,
+    // so nothing newer is dropped.
+    pub(crate) async fn verify_entries(&self) -> Result<VerificationReport, CacheError> {
+        let mut report = VerificationReport::default();
+        let _checkpoint = self.checkpoint_lock.write().await;
+
+        for key in self.store.keys().await {
+            // None for a shredded tenant's entry
+            let failure = match self.store.get(&key).await {
+                Ok(Some(record)) => {
+                    report.checked += 1;
+                    match self.decode_value(&key, &record.value) {
+                        Ok(_) => continue,
+                        Err(e @ (CacheError::IntegrityError | CacheError::DecryptionError)) => {
+                            Some((format!("{:?}", e), Some(record.value)))
+                        }
+                        Err(CacheError::KeyError(_))
+                            if self.encryption.as_ref().is_some_and(|encryption| encryption.is_shredded(&record.value)) =>
+                        {
+                            None
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(None) => continue,
+                // The index points past the end of the segment
+                Err(e) if e.kind() == io::ErrorKind::InvalidData || e.kind() == io::ErrorKind::UnexpectedEof => {
+                    report.checked += 1;
+                    Some((e.to_string(), None))
+                }
+                Err(e) => return Err(CacheError::IoError(e)),
+            };
+
+            let quarantined = match failure {
+                Some((reason, raw)) => Some(self.quarantine.add(&key, reason, raw).await.map_err(CacheError::IoError)?),
+                None => None,
+            };
+            self.map.with_shard(&key, |shard| {
+                if shard.entries.remove(&key).is_some() {
+                    shard.policy.on_remove(&key);
+                }
+            });
+            self.disk_policy().on_remove(&key);
+            self.store.delete(&key).await.map_err(CacheError::IoError)?;
+            match quarantined {
+                Some(entry) => report.dropped.push(entry),
+                None => report.shredded += 1,
+            }
+        }
+
+        // The tombstones bypassed the WAL; make them durable before writers resume
+        self.store.flush().await.map_err(CacheError::IoError)?;
+        if let Some(wal) = &self.wal {
+            wal.checkpoint().await.map_err(CacheError::IoError)?;
+        }
+        Ok(report)
+    }
+
+    // Every entry dropped by a verification pass, including earlier runs.
+    pub(crate) fn quarantined_entries(&self) -> Vec<QuarantinedEntry> {
+        self.quarantine.entries()
+    }
+
+    // An earlier report that could not be read and was moved aside on open.
+    pub(crate) fn set_aside_quarantine_report(&self) -> Option<&Path> {
+        self.quarantine.set_aside_report()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let stored = frame("key", b"value");
+        let range = unframe("key", &stored).unwrap();
+        assert_eq!(&stored[range], b"value");
+
+        let empty = frame("key", b"");
+        assert!(unframe("key", &empty).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_truncation() {
+        let stored = frame("key", b"value");
+        for len in 0..stored.len() {
+            assert!(
+                matches!(unframe("key", &stored[..len]), Err(CacheError::IntegrityError)),
+                "length {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_damage_and_foreign_values() {
+        let stored = frame("key", b"value");
+        for byte in 0..stored.len() {
+            let mut damaged = stored.clone();
+            damaged[byte] ^= 0x01;
+            assert!(matches!(unframe("key", &damaged), Err(CacheError::IntegrityError)), "byte {}", byte);
+        }
+        assert!(matches!(unframe("other", &stored), Err(CacheError::IntegrityError)));
+        assert!(matches!(unframe("key", b"value"), Err(CacheError::IntegrityError)));
+    }
+
+    #[tokio::test]
+    async fn sets_an_unreadable_report_aside() {
+        let dir = std::env::temp_dir().join(format!("trust-quarantine-{}-unreadable", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(REPORT_FILE), b"{ not json").unwrap();
+
+        let quarantine = Quarantine::open(&dir).await.unwrap();
+        assert!(quarantine.entries().is_empty());
+        let aside = quarantine.set_aside_report().unwrap();
+        assert_eq!(std::fs::read(aside).unwrap(), b"{ not json");
+        assert!(!dir.join(REPORT_FILE).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn deletes_leftovers_of_a_shredded_tenant() {
+        let cache = crate::tests::test_cache("verify-shredded", |_| {}).await;
+        cache.set_for_tenant("acme", "key", b"value", None).await.unwrap();
+        cache.set("shared", b"value", None).await.unwrap();
+        // As if a shred was interrupted after the key was destroyed
+        cache.encryption_service().unwrap().shred_tenant("acme").await.unwrap();
+
+        let report = cache.verify_entries().await.unwrap();
+        assert_eq!(report.checked, 2);
+        assert!(report.dropped.is_empty());
+        assert_eq!(report.shredded, 1);
+        assert_eq!(cache.store.keys().await, vec!["shared".to_string()]);
+        assert!(cache.quarantined_entries().is_empty());
+        std::fs::remove_dir_all(&cache.cache_dir).unwrap();
+    }
+}
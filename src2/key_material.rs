@@ -0,0 +1,254 @@
+// Handling of secret key bytes and the files they live in.
+//
+// Long-lived keys (master, keyring and tenant keys) are held in `SecretBytes`,
+// which wipes its bytes when dropped and, on Unix, keeps them in pages that are
+// mlocked so they are never swapped out. The pages are shared by every key,
+// locked once when allocated and never unlocked or freed: mlock works on whole
+// pages and does not nest, so unlocking one key's range would unpin any other
+// key on the same page. Freed slots are wiped and handed out again. Locking is
+// best effort: when RLIMIT_MEMLOCK is exhausted the key still works, it just
+// isn't pinned. Cloning makes another copy inside the locked pages, so no
+// unwiped duplicate is left in ordinary heap memory.
+//
+// Per-value data keys live for a single operation and are kept in plain
+// wiped-on-drop buffers instead, so reads and writes make no extra syscalls.
+//
+// Files holding key material are created 0600 inside a key directory created
+// 0700, and are checked when loaded: a key file another user owns or that is
+// readable by everyone refuses to load, while group access only warns.
+
+use std::{
+    alloc::{self, Layout},
+    fmt, io,
+    path::Path,
+    ptr::NonNull,
+    slice,
+    sync::{Mutex, MutexGuard, OnceLock},
+};
+
+use rand::{rngs::OsRng, RngCore};
+use tokio::fs;
+use zeroize::Zeroize;
+
+use crate::encryption_service::EncryptionError;
+
+const MIN_SLOT_LEN: usize = 32; // Slots are powers of two from here up to a page
+const FALLBACK_PAGE_SIZE: usize = 4096;
+
+static ARENA: OnceLock<Mutex<Arena>> = OnceLock::new();
+
+pub(crate) struct SecretBytes {
+    storage: Storage,
+    len: usize,
+}
+
+enum Storage {
+    Locked { slot: Slot, class: usize },
+    Heap(Box<[u8]>), // Larger than a page; wiped but not pinned
+}
+
+// A slot inside an arena page. Pages are never freed, so the pointer stays valid.
+struct Slot(NonNull<u8>);
+
+// SAFETY: a slot is owned by exactly one `SecretBytes` or sits in a free list,
+// so it is never accessed from two places at once.
+unsafe impl Send for Slot {}
+// SAFETY: shared access only ever reads.
+unsafe impl Sync for Slot {}
+
+struct Arena {
+    page_size: usize,
+    free: Vec<Vec<Slot>>, // Per size class
+}
+
+impl Arena {
+    fn take(&mut self, class: usize) -> Slot {
+        if self.free.len() <= class {
+            self.free.resize_with(class + 1, Vec::new);
+        }
+        if self.free[class].is_empty() {
+            let page = new_locked_page(self.page_size);
+            let slot_len = MIN_SLOT_LEN << class;
+            for offset in (0..self.page_size).step_by(slot_len).rev() {
+                // SAFETY: the offset stays inside the page just allocated.
+                self.free[class].push(Slot(unsafe { NonNull::new_unchecked(page.as_ptr().add(offset)) }));
+            }
+        }
+        self.free[class].pop().unwrap()
+    }
+
+    // The size class for `len` bytes, or None if it needs more than a page.
+    fn class_of(&self, len: usize) -> Option<usize> {
+        let slot_len = len.max(MIN_SLOT_LEN).checked_next_power_of_two()?;
+        (slot_len <= self.page_size).then(|| (slot_len / MIN_SLOT_LEN).trailing_zeros() as usize)
+    }
+}
+
+fn arena() -> MutexGuard<'static, Arena> {
+    ARENA
+        .get_or_init(|| {
+            Mutex::new(Arena {
+                page_size: page_size(),
+                free: Vec::new(),
+            })
+        })
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Allocates a zeroed, page-aligned page and locks it for the life of the process.
+fn new_locked_page(page_size: usize) -> NonNull<u8> {
+    let layout = Layout::from_size_align(page_size, page_size).expect("page size is a power of two");
+    // SAFETY: the layout has a non-zero size.
+    let page = NonNull::new(unsafe { alloc::alloc_zeroed(layout) }).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+    // SAFETY: the page was just allocated with this length.
+    lock(unsafe { slice::from_raw_parts(page.as_ptr(), page_size) });
+    page
+}
+
+impl SecretBytes {
+    pub(crate) fn zeroed(len: usize) -> Self {
+        let mut arena = arena();
+        let storage = match arena.class_of(len) {
+            Some(class) => Storage::Locked {
+                slot: arena.take(class),
+                class,
+            },
+            None => Storage::Heap(vec![0u8; len].into_boxed_slice()),
+        };
+        SecretBytes { storage, len }
+    }
+
+    pub(crate) fn random(len: usize) -> Self {
+        let mut secret = SecretBytes::zeroed(len);
+        OsRng.fill_bytes(secret.expose_mut());
+        secret
+    }
+
+    pub(crate) fn from_slice(bytes: &[u8]) -> Self {
+        let mut secret = SecretBytes::zeroed(bytes.len());
+        secret.expose_mut().copy_from_slice(bytes);
+        secret
+    }
+
+    // Takes over a buffer that already holds a secret and wipes the original.
+    pub(crate) fn from_vec(mut bytes: Vec<u8>) -> Self {
+        let secret = SecretBytes::from_slice(&bytes);
+        bytes.zeroize();
+        secret
+    }
+
+    pub(crate) fn expose(&self) -> &[u8] {
+        match &self.storage {
+            // SAFETY: the slot holds at least `len` bytes and belongs to this value alone.
+            Storage::Locked { slot, .. } => unsafe { slice::from_raw_parts(slot.0.as_ptr(), self.len) },
+            Storage::Heap(bytes) => bytes,
+        }
+    }
+
+    pub(crate) fn expose_mut(&mut self) -> &mut [u8] {
+        match &mut self.storage {
+            // SAFETY: as for `expose`, and `&mut self` rules out other borrows.
+            Storage::Locked { slot, .. } => unsafe { slice::from_raw_parts_mut(slot.0.as_ptr(), self.len) },
+            Storage::Heap(bytes) => bytes,
+        }
+    }
+}
+
+impl Clone for SecretBytes {
+    fn clone(&self) -> Self {
+        SecretBytes::from_slice(self.expose())
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        self.expose_mut().zeroize();
+        if let Storage::Locked { slot, class } = &self.storage {
+            // The page stays locked; the slot goes back for the next key
+            arena().free[*class].push(Slot(slot.0));
+        }
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretBytes([REDACTED; {}])", self.len)
+    }
+}
+
+#[cfg(unix)]
+fn page_size() -> usize {
+    // SAFETY: sysconf has no preconditions.
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 && (size as usize).is_power_of_two() => size as usize,
+        _ => FALLBACK_PAGE_SIZE,
+    }
+}
+
+#[cfg(not(unix))]
+fn page_size() -> usize {
+    FALLBACK_PAGE_SIZE
+}
+
+// Best effort; a page that can't be locked is used all the same.
+#[cfg(unix)]
+fn lock(bytes: &[u8]) {
+    // SAFETY: the range is a live allocation owned by the caller; mlock only
+    // changes its paging, never its contents.
+    unsafe {
+        libc::mlock(bytes.as_ptr().cast(), bytes.len());
+    }
+}
+
+#[cfg(not(unix))]
+fn lock(_bytes: &[u8]) {}
+
+// Creates the key directory, private to the owner when this call creates it.
+pub(crate) async fn create_key_dir(dir: &Path) -> io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    let dir = dir.to_path_buf();
+    tokio::task::spawn_blocking(move || builder.create(&dir))
+        .await
+        .map_err(io::Error::other)?
+}
+
+// Fails with NotFound when there is no file, so callers can create one.
+pub(crate) async fn check_key_file(path: &Path) -> Result<(), EncryptionError> {
+    let metadata = fs::metadata(path).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let mode = metadata.mode() & 0o777;
+        if mode & 0o004 != 0 {
+            return Err(EncryptionError::InsecureKeyFile(format!(
+                "{} is readable by every user (mode {:o}); run `chmod 600` on it",
+                path.display(),
+                mode
+            )));
+        }
+        // SAFETY: geteuid has no preconditions and cannot fail.
+        let euid = unsafe { libc::geteuid() };
+        if metadata.uid() != euid {
+            return Err(EncryptionError::InsecureKeyFile(format!(
+                "{} is owned by uid {}, not by this process (uid {})",
+                path.display(),
+                metadata.uid(),
+                euid
+            )));
+        }
+        if mode & 0o070 != 0 {
+            println!("Warning: key file {} is accessible to its group (mode {:o})", path.display(), mode);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = metadata;
+    Ok(())
+}
@@ -0,0 +1,257 @@
+// Encryption key rotation for DiskCache.
+//
+// Rotating only changes which key new writes use. A re-encryption pass then
+// rewraps the data key of every stored value still protected by an older key,
+// leaving the value's ciphertext as it is, and once no entry depends on a key
+// it can be retired, which destroys it for good. Retiring only checks that
+// nothing depends on the key; it is up to the re-encryption pass to move
+// entries off it first. Backups restore ciphertexts
+// as they were taken, so a key is also kept while the segment snapshot or the
+// backup archive still needs it; taking a fresh backup after re-encryption
+// releases it.
+//
+// The master key that seals the keyring is refreshed separately: when the key
+// provider hands out a new one, only the keyring file is resealed.
+
+use std::{io, sync::Arc, time::Duration};
+
+use serde::Serialize;
+use tokio::time;
+
+use crate::{
+    audit_log::AuditAction,
+    backup_and_recovery::BACKUP_ARCHIVE_FILE,
+    backup_encryption::is_public_key_encrypted,
+    encryption_service::EncryptionService,
+    segment_store, CacheError, DiskCache, BACKUP_DIR,
+};
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct ReencryptionReport {
+    pub(crate) migrated: u64,
+    pub(crate) failed: Vec<(String, String)>, // Entries no key in the keyring could decrypt, and why
+}
+
+impl DiskCache {
+    // Makes a fresh key current and returns its ID.
+    pub(crate) async fn rotate_encryption_key(&self) -> Result<u32, CacheError> {
+        let encryption = self.encryption_service()?;
+        // Recorded against the key being rotated out; the new one isn't known yet
+        let target = encryption.current_key_id().to_string();
+        self.audited(AuditAction::RotateKey, Some(&target), async { Ok(encryption.rotate().await?) })
+            .await
+    }
+
+    // Moves every stored value onto the current key.
+    pub(crate) async fn reencrypt(&self) -> Result<ReencryptionReport, CacheError> {
+        let encryption = self.encryption_service()?;
+        let mut report = ReencryptionReport::default();
+
+        for key in self.store.keys().await {
+            let (record, version) = match self.store.get_versioned(&key).await.map_err(CacheError::IoError)? {
+                Some(found) => found,
+                None => continue, // Deleted since the key list was taken
+            };
+            if !encryption.needs_reencryption(&record.value) {
+                continue;
+            }
+            let ciphertext = match encryption.rewrap(&record.value) {
+                Ok(ciphertext) => ciphertext,
+                Err(e) => {
+                    report.failed.push((key, e.to_string()));
+                    continue;
+                }
+            };
+
+            // Also keeps a promote of the old ciphertext from landing after the resident copy is dropped
+            let _key_lock = self.map.lock_key(&key).await;
+            let replaced = {
+                let _writer = self.checkpoint_lock.read().await;
+                self.store
+                    .replace(&key, version, &ciphertext, record.expiry)
+                    .await
+                    .map_err(CacheError::IoError)?
+            };
+            // If not, a concurrent write got there first, already under the current key
+            if replaced {
+                // The resident copy still holds the old ciphertext
+                self.map.with_shard(&key, |shard| {
+                    if shard.entries.remove(&key).is_some() {
+                        shard.policy.on_remove(&key);
+                    }
+                });
+                report.migrated += 1;
+            }
+        }
+        Ok(report)
+    }
+
+    // Destroys an old key after checking that nothing on disk, backups included,
+    // depends on it.
+    pub(crate) async fn retire_encryption_key(&self, key_id: u32) -> Result<(), CacheError> {
+        self.audited(AuditAction::RetireKey, Some(&key_id.to_string()), self.retire_unused_key(key_id)).await
+    }
+
+    async fn retire_unused_key(&self, key_id: u32) -> Result<(), CacheError> {
+        let encryption = self.encryption_service()?;
+        let mut protected = 0u64;
+        for key in self.store.keys().await {
+            if let Some(record) = self.store.get(&key).await.map_err(CacheError::IoError)? {
+                if encryption.key_id_of(&record.value) == Some(key_id) {
+                    protected += 1;
+                }
+            }
+        }
+        if protected > 0 {
+            return Err(CacheError::KeyError(format!(
+                "key {} still protects {} entries; run reencrypt first",
+                key_id, protected
+            )));
+        }
+        let backed_up = self.backed_up_under(encryption, key_id).await?;
+        if backed_up > 0 {
+            return Err(CacheError::KeyError(format!(
+                "key {} still protects {} backed-up values; take a new backup first",
+                key_id, backed_up
+            )));
+        }
+
+        // The write-ahead log may still hold values encrypted under the key
+        self.save_to_disk().await?;
+        Ok(encryption.retire(key_id).await?)
+    }
+
+    // Counts what a restore would bring back under `key_id`. Archives encrypted
+    // to public keys only keep tenant values wrapped, and tenant keys are never
+    // retired, so they are skipped.
+    async fn backed_up_under(&self, encryption: &EncryptionService, key_id: u32) -> Result<u64, CacheError> {
+        let mut count = 0;
+        match segment_store::read_snapshot(&self.cache_dir.join(BACKUP_DIR)).await {
+            Ok(values) => {
+                count += values
+                    .iter()
+                    .filter(|(_, value)| encryption.key_id_of(value) == Some(key_id))
+                    .count() as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(CacheError::IoError(e)),
+        }
+
+        let archive = match tokio::fs::read(self.cache_dir.join(BACKUP_ARCHIVE_FILE)).await {
+            Ok(archive) => archive,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(count),
+            Err(e) => return Err(CacheError::IoError(e)),
+        };
+        if is_public_key_encrypted(&archive) {
+            return Ok(count);
+        }
+        if encryption.key_id_of(&archive) == Some(key_id) {
+            count += 1; // Sealed under it as a whole
+        }
+        for record in self.read_backup_archive().await? {
+            if encryption.key_id_of(&record.value) == Some(key_id) {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    // Re-reads the master key from its source and reseals the keyring if it
+    // changed. Returns whether it did.
+    pub(crate) async fn refresh_master_key(&self) -> Result<bool, CacheError> {
+        let encryption = self.encryption_service()?;
+        let source = encryption.key_source();
+        let master_key = match encryption.changed_master_key().await {
+            Ok(None) => return Ok(false), // Routine; not worth an audit record
+            Ok(Some(master_key)) => Ok(master_key),
+            Err(e) => Err(e),
+        };
+        self.audited(AuditAction::ResealKeyring, Some(&source), async {
+            encryption.reseal(master_key?).await?;
+            Ok(true)
+        })
+        .await
+    }
+
+    pub(crate) fn encryption_service(&self) -> Result<&EncryptionService, CacheError> {
+        self.encryption
+            .as_ref()
+            .ok_or_else(|| CacheError::KeyError("encryption is disabled".to_string()))
+    }
+}
+
+pub(crate) async fn periodic_reencryption(storage: Arc<DiskCache>, interval: Duration) {
+    let mut interval_timer = time::interval(interval);
+    loop {
+        interval_timer.tick().await;
+        let outdated_keys = storage.encryption.as_ref().map_or(0, |encryption| encryption.key_ids().len() - 1);
+        if outdated_keys == 0 {
+            continue; // Nothing has been rotated out yet
+        }
+        match storage.reencrypt().await {
+            Ok(report) if report.migrated > 0 || !report.failed.is_empty() => {
+                println!("Re-encrypted {} entries, {} failed", report.migrated, report.failed.len())
+            }
+            Ok(_) => {}
+            Err(e) => println!("Re-encryption failed: {:?}", e),
+        }
+    }
+}
+
+pub(crate) async fn periodic_master_key_refresh(storage: Arc<DiskCache>, interval: Duration) {
+    let mut interval_timer = time::interval(interval);
+    loop {
+        interval_timer.tick().await;
+        match storage.refresh_master_key().await {
+            Ok(true) => {
+                let source = storage.encryption.as_ref().map(EncryptionService::key_source).unwrap_or_default();
+                println!("Master key from {} changed; keyring resealed", source)
+            }
+            Ok(false) => {}
+            Err(e) => println!("Master key refresh failed: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rotated_out_keys_retire_once_reencrypted() {
+        let cache = crate::tests::test_cache("rotation-round-trip", |_| {}).await;
+        cache.set("a", b"first", None).await.unwrap();
+        cache.set("b", b"second", None).await.unwrap();
+        let old_key = cache.encryption_service().unwrap().current_key_id();
+
+        let new_key = cache.rotate_encryption_key().await.unwrap();
+        assert_ne!(new_key, old_key);
+        assert!(matches!(cache.retire_encryption_key(old_key).await, Err(CacheError::KeyError(_))));
+        assert!(cache.encryption_service().unwrap().key_ids().contains(&old_key)); // Refused, not re-encrypted
+
+        let report = cache.reencrypt().await.unwrap();
+        assert_eq!(report.migrated, 2);
+        assert!(report.failed.is_empty());
+        cache.retire_encryption_key(old_key).await.unwrap();
+        assert_eq!(cache.encryption_service().unwrap().key_ids(), vec![new_key]);
+
+        // Re-encryption dropped the resident copies, so these decrypt what is on disk
+        assert_eq!(cache.get("a").await.unwrap().as_deref(), Some(&b"first"[..]));
+        assert_eq!(cache.get("b").await.unwrap().as_deref(), Some(&b"second"[..]));
+        std::fs::remove_dir_all(&cache.cache_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_entries_it_cannot_decrypt() {
+        let cache = crate::tests::test_cache("rotation-undecryptable", |_| {}).await;
+        cache.set("a", b"first", None).await.unwrap();
+        cache.rotate_encryption_key().await.unwrap();
+        cache.store.put("b", b"not a ciphertext", None).await.unwrap();
+
+        let report = cache.reencrypt().await.unwrap();
+        assert_eq!(report.migrated, 1);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, "b");
+        std::fs::remove_dir_all(&cache.cache_dir).unwrap();
+    }
+}
@@ -0,0 +1,75 @@
+// Zero-copy reads of large values from sealed segments.
+//
+// Sealed segments are immutable: nothing appends to them again, and the store
+// only ever removes them whole (clear, restore, compaction) instead of
+// truncating or rewriting them in place. A mapping therefore stays valid for
+// as long as anyone holds it; unlinking the file just keeps the old inode alive
+// until the last guard drops its `Arc<Mmap>`. The active segment is still being
+// appended to and can be truncated on recovery, so it is never mapped.
+
+use std::{
+    fs::File,
+    io,
+    ops::{Deref, Range},
+    path::Path,
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use memmap2::Mmap;
+
+// A value borrowed either from a segment mapping or from a shared buffer.
+pub(crate) enum ValueGuard {
+    Mapped { map: Arc<Mmap>, range: Range<usize> },
+    Shared(Bytes),
+}
+
+impl ValueGuard {
+    // Narrows the guard to a sub-range of its bytes without copying.
+    pub(crate) fn slice(self, within: Range<usize>) -> ValueGuard {
+        match self {
+            ValueGuard::Mapped { map, range } => ValueGuard::Mapped {
+                map,
+                range: range.start + within.start..range.start + within.end,
+            },
+            ValueGuard::Shared(bytes) => ValueGuard::Shared(bytes.slice(within)),
+        }
+    }
+}
+
+impl Deref for ValueGuard {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            ValueGuard::Mapped { map, range } => &map[range.clone()],
+            ValueGuard::Shared(bytes) => bytes,
+        }
+    }
+}
+
+impl AsRef<[u8]> for ValueGuard {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+pub(crate) fn map_segment(path: &Path) -> io::Result<Arc<Mmap>> {
+    let file = File::open(path)?;
+    // SAFETY: only sealed segments are mapped, and the store never writes to or
+    // truncates a sealed segment file; see the module comment.
+    let map = unsafe { Mmap::map(&file)? };
+    Ok(Arc::new(map))
+}
+
+pub(crate) fn mapped_value(map: Arc<Mmap>, offset: u64, len: u32) -> io::Result<ValueGuard> {
+    let start = offset as usize;
+    let end = start + len as usize;
+    if end > map.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "record extends past the end of its segment",
+        ));
+    }
+    Ok(ValueGuard::Mapped { map, range: start..end })
+}
@@ -0,0 +1,643 @@
+// Master key material for the encryption keyring.
+//
+// The master key never encrypts cache data itself; it seals the keyring file
+// that holds the data keys. The encryption service gets it from a `KeyProvider`.
+// The built-in providers cover the sources that can be picked in the config: a
+// raw 32-byte key file (generated on first use), a hex-encoded environment
+// variable, a passphrase from the environment stretched with Argon2id, whose
+// salt and cost parameters are stored in a params file so the same key is
+// derived on every start, and an external helper command (a vault CLI, say)
+// that prints the key as hex on stdout. A fifth source hands out a random key
+// held only in memory, for tests and throwaway caches. Relative file paths are
+// resolved against the configured key directory.
+//
+// Like the other settings in .env.example, `ENCRYPTION_KEY` or
+// `ENCRYPTION_PASSPHRASE` in the environment takes precedence over the default
+// key file. If config.json names any other source, a set variable is an error
+// rather than being silently ignored.
+//
+// Providers are wrapped in a `CachingProvider`, which asks the source once and
+// reuses the key until `master_key_refresh_secs` have passed (forever when 0).
+// The next request after that asks the source again. If the source fails at
+// that point, the previous key keeps being served and the failure is logged, so
+// a vault outage does not take a running cache down. When the source starts
+// handing out a different key, the encryption service reseals the keyring
+// under it.
+
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use async_trait::async_trait;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, process::Command, sync::Mutex, time};
+use zeroize::Zeroizing;
+
+use crate::{
+    atomic_write,
+    encryption_service::EncryptionError,
+    key_material::{check_key_file, SecretBytes},
+};
+
+pub(crate) const MASTER_KEY_LEN: usize = 32;
+
+const DEFAULT_KEY_FILE: &str = "encryption_key.bin";
+const DEFAULT_KEY_VAR: &str = "ENCRYPTION_KEY";
+const DEFAULT_PASSPHRASE_VAR: &str = "ENCRYPTION_PASSPHRASE";
+const DEFAULT_KDF_PARAMS_FILE: &str = "encryption_kdf.json";
+const DEFAULT_COMMAND_TIMEOUT_SECS: u64 = 10;
+
+const KDF_ALGORITHM: &str = "argon2id";
+const KDF_SALT_LEN: usize = 16;
+const DEFAULT_KDF_MEMORY_KIB: u32 = 64 * 1024;
+const DEFAULT_KDF_ITERATIONS: u32 = 3;
+const DEFAULT_KDF_PARALLELISM: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub(crate) enum KeySource {
+    KeyFile {
+        #[serde(default = "default_key_file")]
+        path: PathBuf,
+    },
+    EnvHex {
+        #[serde(default = "default_key_var")]
+        var: String,
+    },
+    Passphrase {
+        #[serde(default = "default_passphrase_var")]
+        var: String,
+        #[serde(default = "default_kdf_params_file")]
+        params_path: PathBuf,
+    },
+    Command {
+        program: PathBuf, // Looked up on PATH unless it contains a slash
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default = "default_command_timeout_secs")]
+        timeout_secs: u64,
+    },
+    Ephemeral,
+}
+
+impl Default for KeySource {
+    fn default() -> Self {
+        KeySource::KeyFile {
+            path: default_key_file(),
+        }
+    }
+}
+
+impl KeySource {
+    pub(crate) fn provider(&self, key_dir: &Path) -> Box<dyn KeyProvider> {
+        match self {
+            KeySource::KeyFile { path } => Box::new(KeyFileProvider {
+                path: key_dir.join(path),
+            }),
+            KeySource::EnvHex { var } => Box::new(EnvHexProvider { var: var.clone() }),
+            KeySource::Passphrase { var, params_path } => Box::new(PassphraseProvider {
+                var: var.clone(),
+                params_path: key_dir.join(params_path),
+            }),
+            KeySource::Command {
+                program,
+                args,
+                timeout_secs,
+            } => Box::new(CommandProvider {
+                program: program.clone(),
+                args: args.clone(),
+                timeout: Duration::from_secs(*timeout_secs),
+            }),
+            KeySource::Ephemeral => Box::new(StaticKeyProvider::random()),
+        }
+    }
+}
+
+impl KeySource {
+    pub(crate) fn with_env_override(self) -> Result<KeySource, EncryptionError> {
+        let set: Vec<KeySource> = [
+            (DEFAULT_KEY_VAR, KeySource::EnvHex { var: default_key_var() }),
+            (
+                DEFAULT_PASSPHRASE_VAR,
+                KeySource::Passphrase {
+                    var: default_passphrase_var(),
+                    params_path: default_kdf_params_file(),
+                },
+            ),
+        ]
+        .into_iter()
+        .filter(|(var, _)| env::var_os(var).is_some())
+        .map(|(_, source)| source)
+        .collect();
+
+        let is_default = matches!(&self, KeySource::KeyFile { path } if *path == default_key_file());
+        if is_default {
+            return match set.len() {
+                0 => Ok(self),
+                1 => Ok(set.into_iter().next().unwrap_or(self)),
+                _ => Err(invalid(format!(
+                    "both {} and {} are set; keep the one that holds the master key",
+                    DEFAULT_KEY_VAR, DEFAULT_PASSPHRASE_VAR
+                ))),
+            };
+        }
+        for source in &set {
+            if source.env_var() != self.env_var() {
+                let var = source.env_var().unwrap_or_default();
+                return Err(invalid(format!(
+                    "{} is set, but config.json takes the master key from {}; unset {} or change master_key",
+                    var,
+                    self.provider(Path::new("")).describe(),
+                    var
+                )));
+            }
+        }
+        Ok(self)
+    }
+
+    fn env_var(&self) -> Option<&str> {
+        match self {
+            KeySource::EnvHex { var } | KeySource::Passphrase { var, .. } => Some(var),
+            _ => None,
+        }
+    }
+}
+
+fn default_key_file() -> PathBuf {
+    PathBuf::from(DEFAULT_KEY_FILE)
+}
+
+fn default_key_var() -> String {
+    DEFAULT_KEY_VAR.to_string()
+}
+
+fn default_passphrase_var() -> String {
+    DEFAULT_PASSPHRASE_VAR.to_string()
+}
+
+fn default_kdf_params_file() -> PathBuf {
+    PathBuf::from(DEFAULT_KDF_PARAMS_FILE)
+}
+
+fn default_command_timeout_secs() -> u64 {
+    DEFAULT_COMMAND_TIMEOUT_SECS
+}
+
+#[derive(Clone)]
+pub(crate) struct MasterKey {
+    pub(crate) key: SecretBytes,
+}
+
+#[async_trait]
+pub(crate) trait KeyProvider: Send + Sync {
+    // Names the source in logs and errors. Never includes key bytes.
+    fn describe(&self) -> String;
+
+    async fn master_key(&self) -> Result<MasterKey, EncryptionError>;
+
+    // Keys that die with the process; nothing sealed under them is persisted.
+    fn is_ephemeral(&self) -> bool {
+        false
+    }
+}
+
+pub(crate) struct KeyFileProvider {
+    path: PathBuf,
+}
+
+#[async_trait]
+impl KeyProvider for KeyFileProvider {
+    fn describe(&self) -> String {
+        format!("key file {}", self.path.display())
+    }
+
+    async fn master_key(&self) -> Result<MasterKey, EncryptionError> {
+        match check_key_file(&self.path).await {
+            Ok(()) => {
+                let key = SecretBytes::from_vec(fs::read(&self.path).await?);
+                check_len(&key, &self.describe())?;
+                Ok(MasterKey { key })
+            }
+            Err(EncryptionError::IoError(e)) if e.kind() == io::ErrorKind::NotFound => {
+                let key = SecretBytes::random(MASTER_KEY_LEN);
+                atomic_write::write_secret_async(&self.path, key.expose().to_vec()).await?;
+                Ok(MasterKey { key })
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+pub(crate) struct EnvHexProvider {
+    var: String,
+}
+
+#[async_trait]
+impl KeyProvider for EnvHexProvider {
+    fn describe(&self) -> String {
+        format!("environment variable {}", self.var)
+    }
+
+    async fn master_key(&self) -> Result<MasterKey, EncryptionError> {
+        let value = read_env(&self.var)?;
+        let key = decode_hex(value.trim())
+            .map(SecretBytes::from_vec)
+            .map_err(|reason| invalid(format!("{} {}", self.var, reason)))?;
+        check_len(&key, &self.var)?;
+        Ok(MasterKey { key })
+    }
+}
+
+pub(crate) struct PassphraseProvider {
+    var: String,
+    params_path: PathBuf,
+}
+
+#[async_trait]
+impl KeyProvider for PassphraseProvider {
+    fn describe(&self) -> String {
+        format!("passphrase in {}", self.var)
+    }
+
+    async fn master_key(&self) -> Result<MasterKey, EncryptionError> {
+        let passphrase = read_env(&self.var)?;
+        if passphrase.is_empty() {
+            return Err(invalid(format!("{} is set but empty", self.var)));
+        }
+        let params = load_or_create_kdf_params(&self.params_path).await?;
+        let key = tokio::task::spawn_blocking(move || derive_key(&passphrase, &params))
+            .await
+            .map_err(|e| EncryptionError::IoError(io::Error::other(e)))??;
+        Ok(MasterKey { key })
+    }
+}
+
+// Runs a helper program and reads the key as hex from its stdout. Its stderr is
+// only used to explain a failure, and it is killed if it outlives the timeout.
+pub(crate) struct CommandProvider {
+    program: PathBuf,
+    args: Vec<String>,
+    timeout: Duration,
+}
+
+#[async_trait]
+impl KeyProvider for CommandProvider {
+    fn describe(&self) -> String {
+        format!("key command {}", self.program.display())
+    }
+
+    async fn master_key(&self) -> Result<MasterKey, EncryptionError> {
+        let child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| invalid(format!("could not run {}: {}", self.describe(), e)))?;
+        let output = time::timeout(self.timeout, child.wait_with_output())
+            .await
+            .map_err(|_| invalid(format!("{} did not finish within {:?}", self.describe(), self.timeout)))??;
+
+        let stdout = Zeroizing::new(output.stdout);
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(invalid(format!(
+                "{} failed ({}): {}",
+                self.describe(),
+                output.status,
+                stderr.lines().next().unwrap_or("no error output").trim()
+            )));
+        }
+        let text = std::str::from_utf8(&stdout)
+            .map_err(|_| invalid(format!("{} printed something other than hex", self.describe())))?;
+        let key = decode_hex(text.trim())
+            .map(SecretBytes::from_vec)
+            .map_err(|reason| invalid(format!("output of {} {}", self.describe(), reason)))?;
+        check_len(&key, &self.describe())?;
+        Ok(MasterKey { key })
+    }
+}
+
+// Hands out one random key held in memory. Backs the `ephemeral` source, for
+// tests and for caches whose contents need not survive a restart.
+pub(crate) struct StaticKeyProvider {
+    key: SecretBytes,
+}
+
+impl StaticKeyProvider {
+    pub(crate) fn random() -> Self {
+        StaticKeyProvider {
+            key: SecretBytes::random(MASTER_KEY_LEN),
+        }
+    }
+}
+
+#[async_trait]
+impl KeyProvider for StaticKeyProvider {
+    fn describe(&self) -> String {
+        "ephemeral key".to_string()
+    }
+
+    async fn master_key(&self) -> Result<MasterKey, EncryptionError> {
+        Ok(MasterKey { key: self.key.clone() })
+    }
+
+    fn is_ephemeral(&self) -> bool {
+        true
+    }
+}
+
+// Serves the inner provider's key until it is older than `refresh`. Callers
+// queue behind a fetch in progress rather than each running the source.
+pub(crate) struct CachingProvider {
+    inner: Box<dyn KeyProvider>,
+    refresh: Option<Duration>, // None keeps the first key for the life of the process
+    cached: Mutex<Option<(MasterKey, Instant)>>,
+}
+
+impl CachingProvider {
+    pub(crate) fn new(inner: Box<dyn KeyProvider>, refresh: Duration) -> Self {
+        CachingProvider {
+            inner,
+            refresh: (!refresh.is_zero()).then_some(refresh),
+            cached: Mutex::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl KeyProvider for CachingProvider {
+    fn describe(&self) -> String {
+        self.inner.describe()
+    }
+
+    async fn master_key(&self) -> Result<MasterKey, EncryptionError> {
+        let mut cached = self.cached.lock().await;
+        if let Some((key, fetched_at)) = &*cached {
+            if self.refresh.map_or(true, |refresh| fetched_at.elapsed() < refresh) {
+                return Ok(key.clone());
+            }
+        }
+
+        match (self.inner.master_key().await, &mut *cached) {
+            (Ok(key), cached) => {
+                *cached = Some((key.clone(), Instant::now()));
+                Ok(key)
+            }
+            // Try again after another interval rather than on every request
+            (Err(e), Some((key, fetched_at))) => {
+                println!(
+                    "Warning: could not refresh the master key from {}: {}; keeping the previous key",
+                    self.inner.describe(),
+                    e
+                );
+                *fetched_at = Instant::now();
+                Ok(key.clone())
+            }
+            (Err(e), None) => Err(e),
+        }
+    }
+
+    fn is_ephemeral(&self) -> bool {
+        self.inner.is_ephemeral()
+    }
+}
+
+// Stored next to the keyring; none of it is secret.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KdfParams {
+    algorithm: String,
+    version: u32,
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+    salt: String, // Hex
+}
+
+async fn load_or_create_kdf_params(path: &Path) -> Result<KdfParams, EncryptionError> {
+    match fs::read_to_string(path).await {
+        Ok(contents) => {
+            let params: KdfParams = serde_json::from_str(&contents)
+                .map_err(|e| invalid(format!("KDF params file {} is malformed: {}", path.display(), e)))?;
+            if params.algorithm != KDF_ALGORITHM {
+                return Err(invalid(format!(
+                    "KDF params file {} names unsupported algorithm '{}'",
+                    path.display(),
+                    params.algorithm
+                )));
+            }
+            Ok(params)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let mut salt = [0u8; KDF_SALT_LEN];
+            OsRng.fill_bytes(&mut salt);
+            let params = KdfParams {
+                algorithm: KDF_ALGORITHM.to_string(),
+                version: Version::V0x13 as u32,
+                memory_kib: DEFAULT_KDF_MEMORY_KIB,
+                iterations: DEFAULT_KDF_ITERATIONS,
+                parallelism: DEFAULT_KDF_PARALLELISM,
+                salt: encode_hex(&salt),
+            };
+            let contents = serde_json::to_string_pretty(&params)
+                .map_err(|e| EncryptionError::IoError(io::Error::other(e)))?;
+            atomic_write::write_async(path, contents.into_bytes()).await?;
+            Ok(params)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn derive_key(passphrase: &str, params: &KdfParams) -> Result<SecretBytes, EncryptionError> {
+    let salt = decode_hex(&params.salt).map_err(|reason| invalid(format!("KDF salt {}", reason)))?;
+    let version = Version::try_from(params.version).map_err(|e| invalid(format!("KDF version: {}", e)))?;
+    let cost = Params::new(params.memory_kib, params.iterations, params.parallelism, Some(MASTER_KEY_LEN))
+        .map_err(|e| invalid(format!("KDF parameters: {}", e)))?;
+
+    let mut key = SecretBytes::zeroed(MASTER_KEY_LEN);
+    Argon2::new(Algorithm::Argon2id, version, cost)
+        .hash_password_into(passphrase.as_bytes(), &salt, key.expose_mut())
+        .map_err(|e| invalid(format!("key derivation failed: {}", e)))?;
+    Ok(key)
+}
+
+fn read_env(var: &str) -> Result<Zeroizing<String>, EncryptionError> {
+    env::var(var).map(Zeroizing::new).map_err(|e| match e {
+        env::VarError::NotPresent => invalid(format!("environment variable {} is not set", var)),
+        env::VarError::NotUnicode(_) => invalid(format!("environment variable {} is not valid UTF-8", var)),
+    })
+}
+
+fn check_len(key: &SecretBytes, what: &str) -> Result<(), EncryptionError> {
+    if key.expose().len() == MASTER_KEY_LEN {
+        Ok(())
+    } else {
+        Err(invalid(format!(
+            "{} holds a {}-byte key, expected {} bytes",
+            what,
+            key.expose().len(),
+            MASTER_KEY_LEN
+        )))
+    }
+}
+
+fn invalid(reason: String) -> EncryptionError {
+    EncryptionError::InvalidKeyMaterial(reason)
+}
+
+fn decode_hex(text: &str) -> Result<Vec<u8>, String> {
+    if !text.len().is_multiple_of(2) {
+        return Err(format!("has {} hex digits, which is not a whole number of bytes", text.len()));
+    }
+    text.as_bytes()
+        .chunks(2)
+        .enumerate()
+        .map(|(i, pair)| {
+            let digit = |position: usize| {
+                (pair[position] as char)
+                    .to_digit(16)
+                    .ok_or_else(|| format!("has a non-hex character at position {}", i * 2 + position))
+            };
+            Ok((digit(0)? * 16 + digit(1)?) as u8)
+        })
+        .collect()
+}
+
+pub(crate) fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("trust-master-key-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // The cheapest cost Argon2 accepts, so the tests stay fast
+    fn write_kdf_params(path: &Path, salt: &[u8]) {
+        let params = KdfParams {
+            algorithm: KDF_ALGORITHM.to_string(),
+            version: Version::V0x13 as u32,
+            memory_kib: 8,
+            iterations: 1,
+            parallelism: 1,
+            salt: encode_hex(salt),
+        };
+        std::fs::write(path, serde_json::to_string(&params).unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn static_keys_are_random_and_stable() {
+        let first = StaticKeyProvider::random();
+        let second = StaticKeyProvider::random();
+        let key = first.master_key().await.unwrap().key;
+        assert_eq!(key.expose().len(), MASTER_KEY_LEN);
+        assert_eq!(first.master_key().await.unwrap().key.expose(), key.expose());
+        assert_ne!(second.master_key().await.unwrap().key.expose(), key.expose());
+        assert!(first.is_ephemeral());
+    }
+
+    #[tokio::test]
+    async fn hex_keys_are_parsed_and_checked() {
+        let var = format!("TRUST_TEST_HEX_KEY_{}", std::process::id());
+        let provider = EnvHexProvider { var: var.clone() };
+        assert!(matches!(provider.master_key().await, Err(EncryptionError::InvalidKeyMaterial(_))));
+
+        env::set_var(&var, format!(" {}\n", "aB".repeat(MASTER_KEY_LEN)));
+        assert_eq!(provider.master_key().await.unwrap().key.expose(), &[0xAB; MASTER_KEY_LEN][..]);
+
+        for bad in ["ab".repeat(MASTER_KEY_LEN - 1), "ab".repeat(MASTER_KEY_LEN + 1), "abc".to_string(), "zz".repeat(32)] {
+            env::set_var(&var, bad);
+            assert!(matches!(provider.master_key().await, Err(EncryptionError::InvalidKeyMaterial(_))));
+        }
+        env::remove_var(&var);
+    }
+
+    #[tokio::test]
+    async fn key_files_are_created_once_and_checked() {
+        let dir = test_dir("key-file");
+        let provider = KeySource::default().provider(&dir);
+        let key = provider.master_key().await.unwrap().key;
+        assert_eq!(key.expose().len(), MASTER_KEY_LEN);
+        assert_eq!(provider.master_key().await.unwrap().key.expose(), key.expose());
+
+        atomic_write::write_secret_async(&dir.join(DEFAULT_KEY_FILE), vec![7; 16]).await.unwrap();
+        assert!(matches!(provider.master_key().await, Err(EncryptionError::InvalidKeyMaterial(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn passphrases_derive_the_same_key_from_the_same_params() {
+        let dir = test_dir("passphrase");
+        let var = format!("TRUST_TEST_PASSPHRASE_{}", std::process::id());
+        let provider = |params: &str| PassphraseProvider {
+            var: var.clone(),
+            params_path: dir.join(params),
+        };
+        write_kdf_params(&dir.join("first.json"), &[1; KDF_SALT_LEN]);
+        write_kdf_params(&dir.join("second.json"), &[2; KDF_SALT_LEN]);
+
+        env::set_var(&var, "");
+        assert!(matches!(provider("first.json").master_key().await, Err(EncryptionError::InvalidKeyMaterial(_))));
+        env::set_var(&var, "correct horse battery staple");
+        let key = provider("first.json").master_key().await.unwrap().key;
+        assert_eq!(key.expose().len(), MASTER_KEY_LEN);
+        assert_eq!(provider("first.json").master_key().await.unwrap().key.expose(), key.expose());
+        assert_ne!(provider("second.json").master_key().await.unwrap().key.expose(), key.expose());
+
+        env::set_var(&var, "another passphrase");
+        assert_ne!(provider("first.json").master_key().await.unwrap().key.expose(), key.expose());
+        env::remove_var(&var);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // The only test that touches the default variables, so it can't race another
+    #[test]
+    fn key_variables_replace_the_default_source_or_fail() {
+        env::remove_var(DEFAULT_PASSPHRASE_VAR);
+        env::remove_var(DEFAULT_KEY_VAR);
+        assert!(matches!(KeySource::default().with_env_override(), Ok(KeySource::KeyFile { .. })));
+
+        env::set_var(DEFAULT_KEY_VAR, "00".repeat(MASTER_KEY_LEN));
+        assert!(matches!(KeySource::default().with_env_override(), Ok(KeySource::EnvHex { var }) if var == DEFAULT_KEY_VAR));
+        let configured = KeySource::EnvHex { var: default_key_var() };
+        assert!(configured.with_env_override().is_ok());
+        for other in [
+            KeySource::Ephemeral,
+            KeySource::KeyFile { path: PathBuf::from("other.bin") },
+            KeySource::EnvHex { var: "OTHER_KEY".to_string() },
+        ] {
+            assert!(matches!(other.with_env_override(), Err(EncryptionError::InvalidKeyMaterial(_))));
+        }
+
+        env::set_var(DEFAULT_PASSPHRASE_VAR, "passphrase");
+        assert!(matches!(KeySource::default().with_env_override(), Err(EncryptionError::InvalidKeyMaterial(_))));
+        env::remove_var(DEFAULT_KEY_VAR);
+        assert!(matches!(KeySource::default().with_env_override(), Ok(KeySource::Passphrase { .. })));
+        env::remove_var(DEFAULT_PASSPHRASE_VAR);
+    }
+
+    #[tokio::test]
+    async fn missing_kdf_params_are_created_and_kept() {
+        let dir = test_dir("kdf-params");
+        let path = dir.join(DEFAULT_KDF_PARAMS_FILE);
+        let created = load_or_create_kdf_params(&path).await.unwrap();
+        assert_eq!(created.algorithm, KDF_ALGORITHM);
+        assert_eq!(load_or_create_kdf_params(&path).await.unwrap().salt, created.salt);
+
+        std::fs::write(&path, r#"{"algorithm":"scrypt","version":19,"memory_kib":8,"iterations":1,"parallelism":1,"salt":""}"#).unwrap();
+        assert!(matches!(load_or_create_kdf_params(&path).await, Err(EncryptionError::InvalidKeyMaterial(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
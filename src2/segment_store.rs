@@ -0,0 +1,1079 @@
+// Append-only segment storage backing DiskCache.
+//
+// Every write is appended to the active segment file; once it grows past
+// `max_segment_bytes` it is sealed and a hint file listing the key, offset and
+// length of each record is written next to it, so startup can rebuild the index
+// from hints instead of scanning sealed data. Only the active segment is scanned
+// on open, and a torn record at its tail is truncated away. Large values in
+// sealed segments can be read without copying through `get_ref`. Appends are
+// buffered in memory and every segment read, write and fsync goes through the
+// configured `IoBackend`. Dead bytes are tracked per segment so compaction can
+// rewrite the ones that are mostly garbage.
+//
+// Keys and values must fit the record's u32 lengths and are refused otherwise.
+// A scan treats a length that runs past the end of the file as a torn tail, so
+// a damaged header never makes it allocate more than the file holds.
+//
+// Record layout (little-endian), with an expiry of 0 meaning "never expires":
+//
+//     kind: u8 | expiry_unix_ms: i64 | key_len: u32 | value_len: u32 | key | value
+
+use std::{
+    collections::{hash_map::Entry, BTreeSet, HashMap, HashSet},
+    io::{self, SeekFrom},
+    mem,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use bytes::Bytes;
+use memmap2::Mmap;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, BufReader},
+    sync::Mutex,
+};
+
+use crate::{
+    atomic_write,
+    compaction::Throttle,
+    io_backend::IoBackend,
+    mapped_segments::{map_segment, mapped_value, ValueGuard},
+};
+
+const SEGMENT_EXTENSION: &str = "seg";
+const HINT_EXTENSION: &str = "hint";
+const RECORD_HEADER_LEN: u64 = 17;
+const HINT_HEADER_LEN: usize = 25;
+const WRITE_BUFFER_BYTES: usize = 64 * 1024;
+
+const KIND_PUT: u8 = 1;
+const KIND_DELETE: u8 = 2;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RecordLocation {
+    pub(crate) segment_id: u32,
+    pub(crate) offset: u64, // Offset of the value bytes inside the segment
+    pub(crate) len: u32,
+    pub(crate) expiry: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct StoredRecord {
+    pub(crate) value: Vec<u8>,
+    pub(crate) expiry: Option<i64>, // Absolute Unix time in milliseconds
+}
+
+pub(crate) struct StoredRef {
+    pub(crate) value: ValueGuard,
+    pub(crate) expiry: Option<i64>,
+}
+
+// Identifies one particular record of a key, for compare-and-set rewrites.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RecordVersion {
+    segment_id: u32,
+    offset: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SegmentUsage {
+    pub(crate) len: u64,
+    pub(crate) dead: u64, // Bytes of records the index no longer points at, and of tombstones not known to be needed
+    pub(crate) hint: u64, // Size of the hint file, once the segment is sealed
+}
+
+impl SegmentUsage {
+    pub(crate) fn garbage_ratio(&self) -> f64 {
+        if self.len == 0 {
+            0.0
+        } else {
+            self.dead as f64 / self.len as f64
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CompactedSegment {
+    pub(crate) reclaimed: u64,
+    pub(crate) rewritten: u64,
+}
+
+#[derive(Debug, Clone)]
+struct HintEntry {
+    kind: u8,
+    key: String,
+    offset: u64,
+    len: u32,
+    expiry: Option<i64>,
+}
+
+type SegmentHandle = Arc<std::fs::File>;
+
+struct ActiveSegment {
+    id: u32,
+    file: SegmentHandle,
+    buffer: Vec<u8>, // Appended records not yet handed to the IO backend
+    len: u64,        // Includes the buffered bytes
+    records: Vec<HintEntry>,
+}
+
+struct StoreState {
+    index: HashMap<String, RecordLocation>,
+    expiries: BTreeSet<(i64, String)>, // Ordered by deadline so sweeps never scan the whole index
+    live_bytes: u64,                   // On-disk size of every record the index still points at
+    sealed: Vec<u32>,
+    active: ActiveSegment,
+    mapped: HashMap<u32, Arc<Mmap>>, // Sealed segments mapped so far; rebuilt with the state
+    readers: HashMap<u32, SegmentHandle>, // Open read handles for sealed segments
+    usage: HashMap<u32, SegmentUsage>,
+}
+
+impl StoreState {
+    fn insert(&mut self, key: String, location: RecordLocation) {
+        if let Some(expiry) = location.expiry {
+            self.expiries.insert((expiry, key.clone()));
+        }
+        self.live_bytes += record_size(&key, location.len);
+        if let Some(previous) = self.index.insert(key.clone(), location) {
+            self.live_bytes -= record_size(&key, previous.len);
+            self.mark_dead(previous.segment_id, record_size(&key, previous.len));
+            if let Some(expiry) = previous.expiry {
+                if Some(expiry) != location.expiry {
+                    self.expiries.remove(&(expiry, key));
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<RecordLocation> {
+        let location = self.index.remove(key)?;
+        self.live_bytes -= record_size(key, location.len);
+        self.mark_dead(location.segment_id, record_size(key, location.len));
+        if let Some(expiry) = location.expiry {
+            self.expiries.remove(&(expiry, key.to_string()));
+        }
+        Some(location)
+    }
+
+    fn mark_dead(&mut self, segment_id: u32, bytes: u64) {
+        self.usage.entry(segment_id).or_default().dead += bytes;
+    }
+
+    // Whether the index still points at this exact record.
+    fn is_live(&self, key: &str, segment_id: u32, offset: u64) -> bool {
+        matches!(self.index.get(key), Some(location) if location.segment_id == segment_id && location.offset == offset)
+    }
+}
+
+pub(crate) struct SegmentStore {
+    dir: PathBuf,
+    max_segment_bytes: u64,
+    io: Arc<dyn IoBackend>,
+    state: Mutex<StoreState>,
+    generation: AtomicU64, // Bumped whenever the state is rebuilt from different files
+    snapshots: Mutex<()>,  // Serializes `snapshot_to`, which copies outside the state lock
+}
+
+impl SegmentStore {
+    pub(crate) async fn open(dir: &Path, max_segment_bytes: u64, io: Arc<dyn IoBackend>) -> io::Result<Self> {
+        let state = recover(dir).await?;
+        Ok(SegmentStore {
+            dir: dir.to_path_buf(),
+            max_segment_bytes,
+            io,
+            state: Mutex::new(state),
+            generation: AtomicU64::new(0),
+            snapshots: Mutex::new(()),
+        })
+    }
+
+    pub(crate) async fn put(&self, key: &str, value: &[u8], expiry: Option<i64>) -> io::Result<()> {
+        let mut state = self.state.lock().await;
+        let location = self.append(&mut state, KIND_PUT, key, value, expiry).await?;
+        state.insert(key.to_string(), location);
+        Ok(())
+    }
+
+    pub(crate) async fn delete(&self, key: &str) -> io::Result<()> {
+        let mut state = self.state.lock().await;
+        if state.remove(key).is_some() {
+            let tombstone = self.append(&mut state, KIND_DELETE, key, &[], None).await?;
+            state.mark_dead(tombstone.segment_id, record_size(key, 0));
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn get(&self, key: &str) -> io::Result<Option<StoredRecord>> {
+        Ok(self.get_versioned(key).await?.map(|(record, _)| record))
+    }
+
+    // Like `get`, plus the version `replace` needs to detect newer writes.
+    pub(crate) async fn get_versioned(&self, key: &str) -> io::Result<Option<(StoredRecord, RecordVersion)>> {
+        let (location, handle) = {
+            let mut state = self.state.lock().await;
+            let location = match state.index.get(key) {
+                Some(location) => *location,
+                None => return Ok(None),
+            };
+            (location, self.handle(&mut state, location.segment_id).await?)
+        };
+        let record = StoredRecord {
+            value: self.io.read_at(handle, location.offset, location.len as usize).await?,
+            expiry: location.expiry,
+        };
+        let version = RecordVersion {
+            segment_id: location.segment_id,
+            offset: location.offset,
+        };
+        Ok(Some((record, version)))
+    }
+
+    // Overwrites a key only if its record is still the one at `version`, so a
+    // background rewrite never clobbers a newer write. Returns whether it did.
+    pub(crate) async fn replace(
+        &self,
+        key: &str,
+        version: RecordVersion,
+        value: &[u8],
+        expiry: Option<i64>,
+    ) -> io::Result<bool> {
+        let mut state = self.state.lock().await;
+        if !state.is_live(key, version.segment_id, version.offset) {
+            return Ok(false);
+        }
+        let location = self.append(&mut state, KIND_PUT, key, value, expiry).await?;
+        state.insert(key.to_string(), location);
+        Ok(true)
+    }
+
+    // Like `get`, but values of at least `min_mapped_len` bytes in sealed
+    // segments are borrowed straight from a memory mapping instead of copied.
+    pub(crate) async fn get_ref(&self, key: &str, min_mapped_len: u32) -> io::Result<Option<StoredRef>> {
+        let (location, handle) = {
+            let mut state = self.state.lock().await;
+            let location = match state.index.get(key) {
+                Some(location) => *location,
+                None => return Ok(None),
+            };
+            if location.segment_id != state.active.id && location.len >= min_mapped_len {
+                let map = match state.mapped.get(&location.segment_id) {
+                    Some(map) => map.clone(),
+                    None => {
+                        let map = map_segment(&segment_path(&self.dir, location.segment_id))?;
+                        state.mapped.insert(location.segment_id, map.clone());
+                        map
+                    }
+                };
+                return Ok(Some(StoredRef {
+                    value: mapped_value(map, location.offset, location.len)?,
+                    expiry: location.expiry,
+                }));
+            }
+            (location, self.handle(&mut state, location.segment_id).await?)
+        };
+
+        // Small or still in the active segment: an ordinary read
+        let value = self.io.read_at(handle, location.offset, location.len as usize).await?;
+        Ok(Some(StoredRef {
+            value: ValueGuard::Shared(Bytes::from(value)),
+            expiry: location.expiry,
+        }))
+    }
+
+    // Returns a handle to read a segment through. Records still sitting in the
+    // write buffer are written out first so the read can see them.
+    async fn handle(&self, state: &mut StoreState, segment_id: u32) -> io::Result<SegmentHandle> {
+        if segment_id == state.active.id {
+            self.write_buffered(&mut state.active).await?;
+            return Ok(state.active.file.clone());
+        }
+        if let Some(handle) = state.readers.get(&segment_id) {
+            return Ok(handle.clone());
+        }
+        let file = File::open(segment_path(&self.dir, segment_id)).await?;
+        let handle = Arc::new(file.into_std().await);
+        state.readers.insert(segment_id, handle.clone());
+        Ok(handle)
+    }
+
+    async fn write_buffered(&self, active: &mut ActiveSegment) -> io::Result<()> {
+        if active.buffer.is_empty() {
+            return Ok(());
+        }
+        let data = mem::take(&mut active.buffer);
+        let offset = active.len - data.len() as u64;
+        self.io.write_at(active.file.clone(), offset, data).await
+    }
+
+    pub(crate) async fn keys(&self) -> Vec<String> {
+        self.state.lock().await.index.keys().cloned().collect()
+    }
+
+    pub(crate) fn io_backend(&self) -> &'static str {
+        self.io.name()
+    }
+
+    pub(crate) async fn live_bytes(&self) -> u64 {
+        self.state.lock().await.live_bytes
+    }
+
+    // Keys whose deadline is at or before `now` (Unix milliseconds).
+    pub(crate) async fn expired_keys(&self, now: i64) -> Vec<String> {
+        let state = self.state.lock().await;
+        state
+            .expiries
+            .iter()
+            .take_while(|(expiry, _)| *expiry <= now)
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+
+    // Pushes buffered records to the OS and fsyncs the active segment.
+    pub(crate) async fn flush(&self) -> io::Result<()> {
+        let mut state = self.state.lock().await;
+        self.write_buffered(&mut state.active).await?;
+        self.io.sync_data(state.active.file.clone()).await
+    }
+
+    // Usage of every sealed segment, oldest first.
+    pub(crate) async fn segment_usage(&self) -> Vec<(u32, SegmentUsage)> {
+        let state = self.state.lock().await;
+        state
+            .sealed
+            .iter()
+            .map(|id| (*id, state.usage.get(id).copied().unwrap_or_default()))
+            .collect()
+    }
+
+    pub(crate) async fn dead_bytes(&self) -> u64 {
+        self.state.lock().await.usage.values().map(|usage| usage.dead).sum()
+    }
+
+    // What the segment and hint files take up on disk, dead records included.
+    pub(crate) async fn disk_bytes(&self) -> u64 {
+        self.state.lock().await.usage.values().map(|usage| usage.len + usage.hint).sum()
+    }
+
+    // Rebuilds the index from the files on disk, discarding unflushed writes.
+    pub(crate) async fn reload(&self) -> io::Result<()> {
+        let mut state = self.state.lock().await;
+        *state = recover(&self.dir).await?;
+        self.generation.fetch_add(1, Ordering::Release);
+        Ok(())
+    }
+
+    pub(crate) async fn clear(&self) -> io::Result<()> {
+        let mut state = self.state.lock().await;
+        remove_store_files(&self.dir).await?;
+        *state = recover(&self.dir).await?;
+        self.generation.fetch_add(1, Ordering::Release);
+        Ok(())
+    }
+
+    // Copies the live records of a sealed segment into the active segment and
+    // deletes it. Records are re-checked against the index right before they are
+    // copied, so writes racing with compaction always win. A tombstone is kept
+    // only while an older segment still holds a put for its key, and a kept one
+    // is not counted as garbage in its new segment, so a segment full of needed
+    // tombstones is not rewritten over and over. `progress` counts the bytes of
+    // the segment walked so far.
+    pub(crate) async fn compact_segment(
+        &self,
+        segment_id: u32,
+        throttle: &mut Throttle,
+        progress: &AtomicU64,
+    ) -> io::Result<CompactedSegment> {
+        let generation = self.generation.load(Ordering::Acquire);
+        let (handle, len, older) = {
+            let mut state = self.state.lock().await;
+            if !state.sealed.contains(&segment_id) {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "segment is not sealed"));
+            }
+            let len = state.usage.get(&segment_id).map_or(0, |usage| usage.len);
+            let older: Vec<u32> = state.sealed.iter().copied().filter(|id| *id < segment_id).collect();
+            (self.handle(&mut state, segment_id).await?, len, older)
+        };
+        let records = read_records(&self.dir, segment_id).await?;
+        let tombstoned: HashSet<&str> = records
+            .iter()
+            .filter(|record| record.kind == KIND_DELETE)
+            .map(|record| record.key.as_str())
+            .collect();
+        let shadowed = keys_with_puts(&self.dir, &older, &tombstoned).await?;
+
+        let mut rewritten = 0u64;
+        for record in records {
+            let size = record_size(&record.key, record.len);
+            progress.fetch_add(size, Ordering::Relaxed);
+            match record.kind {
+                KIND_PUT => {
+                    if !self.state.lock().await.is_live(&record.key, segment_id, record.offset) {
+                        continue;
+                    }
+                    throttle.consume(size).await;
+                    let value = self.io.read_at(handle.clone(), record.offset, record.len as usize).await?;
+
+                    let mut state = self.state.lock().await;
+                    self.check_generation(generation)?;
+                    if state.is_live(&record.key, segment_id, record.offset) {
+                        let location = self.append(&mut state, KIND_PUT, &record.key, &value, record.expiry).await?;
+                        state.insert(record.key, location);
+                        rewritten += size;
+                    }
+                }
+                KIND_DELETE => {
+                    let mut state = self.state.lock().await;
+                    self.check_generation(generation)?;
+                    if shadowed.contains(&record.key) && !state.index.contains_key(&record.key) {
+                        throttle.consume(size).await;
+                        self.append(&mut state, KIND_DELETE, &record.key, &[], None).await?;
+                        rewritten += size;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut state = self.state.lock().await;
+        self.check_generation(generation)?;
+        // The copies must be durable before the originals go away
+        self.write_buffered(&mut state.active).await?;
+        self.io.sync_data(state.active.file.clone()).await?;
+
+        state.sealed.retain(|id| *id != segment_id);
+        state.usage.remove(&segment_id);
+        state.readers.remove(&segment_id);
+        // Outstanding guards keep their own `Arc` to the mapping of the unlinked file
+        state.mapped.remove(&segment_id);
+        fs::remove_file(segment_path(&self.dir, segment_id)).await?;
+        match fs::remove_file(hint_path(&self.dir, segment_id)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        atomic_write::sync_dir_async(&self.dir).await?;
+
+        Ok(CompactedSegment {
+            reclaimed: len.saturating_sub(rewritten),
+            rewritten,
+        })
+    }
+
+    fn check_generation(&self, generation: u64) -> io::Result<()> {
+        if self.generation.load(Ordering::Acquire) == generation {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::Interrupted, "store was replaced during compaction"))
+        }
+    }
+
+    // Copies a consistent snapshot of every segment and hint file into `target`.
+    // Each file is replaced atomically and stale ones are only removed once the
+    // new set is complete, so a crash midway leaves a loadable snapshot behind.
+    //
+    // The state lock is only held to open the files and note how long the
+    // active segment is; the copying happens without it. Writes that land
+    // meanwhile only grow the active segment past the noted length, and a
+    // sealed segment compacted away is still read through its open handle.
+    pub(crate) async fn snapshot_to(&self, target: &Path) -> io::Result<()> {
+        let _snapshot = self.snapshots.lock().await;
+        let mut files = Vec::new();
+        {
+            let mut state = self.state.lock().await;
+            self.write_buffered(&mut state.active).await?;
+            self.io.sync_data(state.active.file.clone()).await?;
+            for id in &state.sealed {
+                let segment = File::open(segment_path(&self.dir, *id)).await?.into_std().await;
+                let len = segment.metadata()?.len();
+                files.push((segment, len, segment_path(target, *id)));
+                match File::open(hint_path(&self.dir, *id)).await {
+                    Ok(hint) => {
+                        let hint = hint.into_std().await;
+                        let len = hint.metadata()?.len();
+                        files.push((hint, len, hint_path(target, *id)));
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            let active = File::open(segment_path(&self.dir, state.active.id)).await?.into_std().await;
+            files.push((active, state.active.len, segment_path(target, state.active.id)));
+        }
+
+        fs::create_dir_all(target).await?;
+        let mut copied = Vec::new();
+        for (file, len, path) in files {
+            atomic_write::copy_prefix_async(file, len, &path).await?;
+            copied.push(path);
+        }
+        remove_stale_store_files(target, &copied).await
+    }
+
+    // Replaces the store contents with a snapshot previously written by `snapshot_to`.
+    pub(crate) async fn restore_from(&self, source: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().await;
+        let mut copied = Vec::new();
+        let mut entries = fs::read_dir(source).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if is_store_file(&entry.path()) {
+                let target = self.dir.join(entry.file_name());
+                atomic_write::copy_async(&entry.path(), &target).await?;
+                copied.push(target);
+            }
+        }
+        remove_stale_store_files(&self.dir, &copied).await?;
+        *state = recover(&self.dir).await?;
+        self.generation.fetch_add(1, Ordering::Release);
+        Ok(())
+    }
+
+    async fn append(
+        &self,
+        state: &mut StoreState,
+        kind: u8,
+        key: &str,
+        value: &[u8],
+        expiry: Option<i64>,
+    ) -> io::Result<RecordLocation> {
+        check_record_lens(key.len(), value.len())?;
+        let record_len = record_size(key, value.len() as u32);
+        if state.active.len > 0 && state.active.len + record_len > self.max_segment_bytes {
+            self.rotate(state).await?;
+        }
+        state.usage.entry(state.active.id).or_default().len += record_len;
+
+        let mut header = [0u8; RECORD_HEADER_LEN as usize];
+        header[0] = kind;
+        header[1..9].copy_from_slice(&expiry.unwrap_or(0).to_le_bytes());
+        header[9..13].copy_from_slice(&(key.len() as u32).to_le_bytes());
+        header[13..17].copy_from_slice(&(value.len() as u32).to_le_bytes());
+
+        let active = &mut state.active;
+        active.buffer.extend_from_slice(&header);
+        active.buffer.extend_from_slice(key.as_bytes());
+        active.buffer.extend_from_slice(value);
+
+        let offset = active.len + RECORD_HEADER_LEN + key.len() as u64;
+        active.len += record_len;
+        active.records.push(HintEntry {
+            kind,
+            key: key.to_string(),
+            offset,
+            len: value.len() as u32,
+            expiry,
+        });
+
+        let location = RecordLocation {
+            segment_id: active.id,
+            offset,
+            len: value.len() as u32,
+            expiry,
+        };
+        if active.buffer.len() >= WRITE_BUFFER_BYTES {
+            self.write_buffered(active).await?;
+        }
+        Ok(location)
+    }
+
+    // Seals the active segment, writes its hint file and starts a new one.
+    async fn rotate(&self, state: &mut StoreState) -> io::Result<()> {
+        self.write_buffered(&mut state.active).await?;
+        self.io.sync_data(state.active.file.clone()).await?;
+        let hint = write_hint(&hint_path(&self.dir, state.active.id), &state.active.records).await?;
+        state.usage.entry(state.active.id).or_default().hint = hint;
+
+        state.sealed.push(state.active.id);
+        state.active = open_active(&self.dir, state.active.id + 1, 0).await?;
+        // The new segment's directory entry must be durable before records land in it
+        atomic_write::sync_dir_async(&self.dir).await
+    }
+}
+
+// Reads the live values of a snapshot written by `snapshot_to` without
+// modifying any of its files.
+pub(crate) async fn read_snapshot(dir: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut index = HashMap::new();
+    let mut expiries = BTreeSet::new();
+    for id in segment_ids(dir).await? {
+        apply_records(&mut index, &mut expiries, id, &read_records(dir, id).await?);
+    }
+
+    let mut files = HashMap::new();
+    let mut values = Vec::with_capacity(index.len());
+    for (key, location) in index {
+        let file = match files.entry(location.segment_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(File::open(segment_path(dir, location.segment_id)).await?),
+        };
+        file.seek(SeekFrom::Start(location.offset)).await?;
+        let mut value = vec![0u8; location.len as usize];
+        file.read_exact(&mut value).await?;
+        values.push((key, value));
+    }
+    Ok(values)
+}
+
+// IDs of the segment files in `dir`, oldest first.
+async fn segment_ids(dir: &Path) -> io::Result<Vec<u32>> {
+    let mut ids = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|ext| ext.to_str()) == Some(SEGMENT_EXTENSION) {
+            if let Some(id) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u32>().ok()) {
+                ids.push(id);
+            }
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+async fn recover(dir: &Path) -> io::Result<StoreState> {
+    fs::create_dir_all(dir).await?;
+
+    let mut ids = segment_ids(dir).await?;
+
+    let active_id = ids.pop().unwrap_or(1);
+    let mut index = HashMap::new();
+    let mut expiries = BTreeSet::new();
+
+    for &id in &ids {
+        let records = read_records(dir, id).await?;
+        apply_records(&mut index, &mut expiries, id, &records);
+    }
+
+    let (records, valid_len) = match fs::metadata(segment_path(dir, active_id)).await {
+        Ok(_) => scan_segment(&segment_path(dir, active_id)).await?,
+        Err(_) => (Vec::new(), 0),
+    };
+    apply_records(&mut index, &mut expiries, active_id, &records);
+
+    let mut active = open_active(dir, active_id, valid_len).await?;
+    active.records = records;
+    let live_bytes = index.iter().map(|(key, location)| record_size(key, location.len)).sum();
+
+    // Whatever the index doesn't point at is dead
+    let mut usage = HashMap::new();
+    for &id in &ids {
+        let len = fs::metadata(segment_path(dir, id)).await?.len();
+        let hint = fs::metadata(hint_path(dir, id)).await.map_or(0, |metadata| metadata.len());
+        usage.insert(id, SegmentUsage { len, dead: len, hint });
+    }
+    usage.insert(active_id, SegmentUsage { len: valid_len, dead: valid_len, hint: 0 });
+    for (key, location) in &index {
+        if let Some(segment) = usage.get_mut(&location.segment_id) {
+            segment.dead = segment.dead.saturating_sub(record_size(key, location.len));
+        }
+    }
+
+    Ok(StoreState {
+        index,
+        expiries,
+        live_bytes,
+        sealed: ids,
+        active,
+        mapped: HashMap::new(),
+        readers: HashMap::new(),
+        usage,
+    })
+}
+
+async fn open_active(dir: &Path, id: u32, valid_len: u64) -> io::Result<ActiveSegment> {
+    // Not opened in append mode: the IO backend writes at explicit offsets
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false) // Trimmed to `valid_len` below
+        .read(true)
+        .write(true)
+        .open(segment_path(dir, id))
+        .await?;
+    // Drop any torn record left behind by a crash mid-append
+    file.set_len(valid_len).await?;
+
+    Ok(ActiveSegment {
+        id,
+        file: Arc::new(file.into_std().await),
+        buffer: Vec::new(),
+        len: valid_len,
+        records: Vec::new(),
+    })
+}
+
+fn apply_records(
+    index: &mut HashMap<String, RecordLocation>,
+    expiries: &mut BTreeSet<(i64, String)>,
+    segment_id: u32,
+    records: &[HintEntry],
+) {
+    for record in records {
+        let previous = match record.kind {
+            KIND_PUT => index.insert(
+                record.key.clone(),
+                RecordLocation {
+                    segment_id,
+                    offset: record.offset,
+                    len: record.len,
+                    expiry: record.expiry,
+                },
+            ),
+            KIND_DELETE => index.remove(&record.key),
+            _ => None,
+        };
+        if let Some(expiry) = previous.and_then(|location| location.expiry) {
+            expiries.remove(&(expiry, record.key.clone()));
+        }
+        if let (KIND_PUT, Some(expiry)) = (record.kind, record.expiry) {
+            expiries.insert((expiry, record.key.clone()));
+        }
+    }
+}
+
+// The records of a sealed segment, from its hint file if it has a readable one.
+async fn read_records(dir: &Path, segment_id: u32) -> io::Result<Vec<HintEntry>> {
+    match read_hint(&hint_path(dir, segment_id)).await {
+        Ok(records) => Ok(records),
+        Err(_) => Ok(scan_segment(&segment_path(dir, segment_id)).await?.0),
+    }
+}
+
+// Which of `keys` have a put in any of the given segments.
+async fn keys_with_puts(dir: &Path, segment_ids: &[u32], keys: &HashSet<&str>) -> io::Result<HashSet<String>> {
+    let mut found = HashSet::new();
+    if keys.is_empty() {
+        return Ok(found);
+    }
+    for &id in segment_ids {
+        for record in read_records(dir, id).await? {
+            if record.kind == KIND_PUT && keys.contains(record.key.as_str()) {
+                found.insert(record.key);
+            }
+        }
+    }
+    Ok(found)
+}
+
+pub(crate) fn check_record_lens(key_len: usize, value_len: usize) -> io::Result<()> {
+    if u32::try_from(key_len).is_err() || u32::try_from(value_len).is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("record too large: {} byte key, {} byte value", key_len, value_len),
+        ));
+    }
+    Ok(())
+}
+
+// Returns every complete record in the segment and the length of the valid prefix.
+async fn scan_segment(path: &Path) -> io::Result<(Vec<HintEntry>, u64)> {
+    let file = File::open(path).await?;
+    let file_len = file.metadata().await?.len();
+    let mut reader = BufReader::new(file);
+    let mut records = Vec::new();
+    let mut position = 0u64;
+
+    loop {
+        let mut header = [0u8; RECORD_HEADER_LEN as usize];
+        match reader.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let kind = header[0];
+        let expiry = decode_expiry(&header[1..9]);
+        let key_len = u32::from_le_bytes(header[9..13].try_into().unwrap()) as usize;
+        let value_len = u32::from_le_bytes(header[13..17].try_into().unwrap());
+        if kind != KIND_PUT && kind != KIND_DELETE {
+            break;
+        }
+        let remaining = file_len.saturating_sub(position + RECORD_HEADER_LEN);
+        if key_len as u64 + value_len as u64 > remaining {
+            break; // Torn, or a damaged length
+        }
+
+        let mut key = vec![0u8; key_len];
+        if reader.read_exact(&mut key).await.is_err() {
+            break;
+        }
+        let key = match String::from_utf8(key) {
+            Ok(key) => key,
+            Err(_) => break,
+        };
+
+        let mut value = (&mut reader).take(value_len as u64);
+        let skipped = tokio::io::copy(&mut value, &mut tokio::io::sink()).await?;
+        if skipped != value_len as u64 {
+            break;
+        }
+
+        let offset = position + RECORD_HEADER_LEN + key_len as u64;
+        records.push(HintEntry {
+            kind,
+            key,
+            offset,
+            len: value_len,
+            expiry,
+        });
+        position = offset + value_len as u64;
+    }
+
+    Ok((records, position))
+}
+
+// Returns the size of the written file.
+async fn write_hint(path: &Path, records: &[HintEntry]) -> io::Result<u64> {
+    let mut buffer = Vec::new();
+    for record in records {
+        buffer.push(record.kind);
+        buffer.extend_from_slice(&record.expiry.unwrap_or(0).to_le_bytes());
+        buffer.extend_from_slice(&(record.key.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(&record.offset.to_le_bytes());
+        buffer.extend_from_slice(&record.len.to_le_bytes());
+        buffer.extend_from_slice(record.key.as_bytes());
+    }
+    let len = buffer.len() as u64;
+    atomic_write::write_async(path, buffer).await?;
+    Ok(len)
+}
+
+async fn read_hint(path: &Path) -> io::Result<Vec<HintEntry>> {
+    let buffer = fs::read(path).await?;
+    let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "corrupt hint file");
+
+    let mut records = Vec::new();
+    let mut cursor = 0usize;
+    while cursor < buffer.len() {
+        let header = buffer.get(cursor..cursor + HINT_HEADER_LEN).ok_or_else(corrupt)?;
+        let kind = header[0];
+        let expiry = decode_expiry(&header[1..9]);
+        let key_len = u32::from_le_bytes(header[9..13].try_into().unwrap()) as usize;
+        let offset = u64::from_le_bytes(header[13..21].try_into().unwrap());
+        let len = u32::from_le_bytes(header[21..25].try_into().unwrap());
+        cursor += HINT_HEADER_LEN;
+
+        let key = buffer.get(cursor..cursor + key_len).ok_or_else(corrupt)?;
+        let key = String::from_utf8(key.to_vec()).map_err(|_| corrupt())?;
+        cursor += key_len;
+
+        records.push(HintEntry {
+            kind,
+            key,
+            offset,
+            len,
+            expiry,
+        });
+    }
+    Ok(records)
+}
+
+async fn remove_store_files(dir: &Path) -> io::Result<()> {
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        if is_store_file(&entry.path()) {
+            fs::remove_file(entry.path()).await?;
+        }
+    }
+    Ok(())
+}
+
+// Removes store files in `dir` that are not in `keep`, then syncs the directory.
+async fn remove_stale_store_files(dir: &Path, keep: &[PathBuf]) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if is_store_file(&entry.path()) && !keep.contains(&entry.path()) {
+            fs::remove_file(entry.path()).await?;
+        }
+    }
+    atomic_write::sync_dir_async(dir).await
+}
+
+fn is_store_file(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some(SEGMENT_EXTENSION) | Some(HINT_EXTENSION)
+    )
+}
+
+// Bytes a record occupies in its segment, header included.
+pub(crate) fn record_size(key: &str, value_len: u32) -> u64 {
+    RECORD_HEADER_LEN + key.len() as u64 + value_len as u64
+}
+
+fn decode_expiry(bytes: &[u8]) -> Option<i64> {
+    match i64::from_le_bytes(bytes.try_into().unwrap()) {
+        0 => None,
+        expiry => Some(expiry),
+    }
+}
+
+fn segment_path(dir: &Path, id: u32) -> PathBuf {
+    dir.join(format!("{:08}.{}", id, SEGMENT_EXTENSION))
+}
+
+fn hint_path(dir: &Path, id: u32) -> PathBuf {
+    dir.join(format!("{:08}.{}", id, HINT_EXTENSION))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io_backend::{new_backend, IoBackendKind};
+
+    async fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("trust-segments-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir_all(&dir).await.unwrap();
+        dir
+    }
+
+    async fn open_store(dir: &Path, max_segment_bytes: u64) -> SegmentStore {
+        SegmentStore::open(dir, max_segment_bytes, new_backend(IoBackendKind::TokioFs, 0)).await.unwrap()
+    }
+
+    fn record(kind: u8, key: &str, value: &[u8], expiry: Option<i64>) -> Vec<u8> {
+        let mut record = vec![kind];
+        record.extend_from_slice(&expiry.unwrap_or(0).to_le_bytes());
+        record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        record.extend_from_slice(key.as_bytes());
+        record.extend_from_slice(value);
+        record
+    }
+
+    #[tokio::test]
+    async fn round_trips_through_reopen() {
+        let dir = test_dir("round-trip").await;
+        {
+            // Small segments, so some records end up sealed behind hint files
+            let store = open_store(&dir, 64).await;
+            for i in 0..10 {
+                store.put(&format!("key{}", i), format!("value{}", i).as_bytes(), Some(1000 + i)).await.unwrap();
+            }
+            store.delete("key3").await.unwrap();
+            store.flush().await.unwrap();
+        }
+
+        let store = open_store(&dir, 64).await;
+        assert_eq!(store.keys().await.len(), 9);
+        assert!(store.get("key3").await.unwrap().is_none());
+        let found = store.get("key7").await.unwrap().unwrap();
+        assert_eq!(found.value, b"value7");
+        assert_eq!(found.expiry, Some(1007));
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn snapshots_hold_what_was_written_before_them() {
+        let dir = test_dir("snapshot").await;
+        let target = dir.join("snapshot");
+        let store = open_store(&dir, 64).await; // Every record seals a segment
+        for key in ["a", "b", "c"] {
+            store.put(key, &[key.as_bytes()[0]; 40], None).await.unwrap();
+        }
+        store.snapshot_to(&target).await.unwrap();
+        store.put("d", b"later", None).await.unwrap();
+        store.delete("a").await.unwrap();
+
+        let mut values = read_snapshot(&target).await.unwrap();
+        values.sort();
+        let keys: Vec<_> = values.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["a", "b", "c"]);
+        assert_eq!(values[1].1, vec![b'b'; 40]);
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn scan_stops_at_a_torn_record() {
+        let dir = test_dir("torn").await;
+        let path = segment_path(&dir, 0);
+        let mut contents = record(KIND_PUT, "a", b"first", None);
+        let intact_len = contents.len();
+        contents.extend_from_slice(&record(KIND_PUT, "b", b"second", Some(5)));
+
+        for len in intact_len..contents.len() {
+            fs::write(&path, &contents[..len]).await.unwrap();
+            let (records, valid_len) = scan_segment(&path).await.unwrap();
+            assert_eq!(records.len(), 1, "length {}", len);
+            assert_eq!(valid_len, intact_len as u64);
+        }
+        fs::write(&path, &contents).await.unwrap();
+        let (records, valid_len) = scan_segment(&path).await.unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[1].key.as_str(), records[1].len, records[1].expiry), ("b", 6, Some(5)));
+        assert_eq!(valid_len, contents.len() as u64);
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn scan_stops_at_an_unknown_record_kind() {
+        let dir = test_dir("unknown-kind").await;
+        let path = segment_path(&dir, 0);
+        let mut contents = record(KIND_PUT, "a", b"first", None);
+        let intact_len = contents.len();
+        contents.extend_from_slice(&record(0xFF, "b", b"second", None));
+        fs::write(&path, &contents).await.unwrap();
+
+        let (records, valid_len) = scan_segment(&path).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(valid_len, intact_len as u64);
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn scan_stops_at_a_length_past_the_end_of_the_file() {
+        let dir = test_dir("bad-length").await;
+        let path = segment_path(&dir, 0);
+        let mut contents = record(KIND_PUT, "a", b"first", None);
+        let intact_len = contents.len();
+        let mut damaged = record(KIND_PUT, "b", b"second", None);
+        damaged[9..13].copy_from_slice(&u32::MAX.to_le_bytes());
+        contents.extend_from_slice(&damaged);
+        fs::write(&path, &contents).await.unwrap();
+
+        let (records, valid_len) = scan_segment(&path).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(valid_len, intact_len as u64);
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn rejects_lengths_that_do_not_fit_a_record() {
+        assert!(check_record_lens(3, u32::MAX as usize).is_ok());
+        let error = check_record_lens(3, u32::MAX as usize + 1).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(check_record_lens(u32::MAX as usize + 1, 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn hint_files_round_trip_and_reject_truncation() {
+        let dir = test_dir("hint").await;
+        let path = hint_path(&dir, 0);
+        let entries = vec![
+            HintEntry { kind: KIND_PUT, key: "a".to_string(), offset: 18, len: 5, expiry: Some(9) },
+            HintEntry { kind: KIND_DELETE, key: "a".to_string(), offset: 41, len: 0, expiry: None },
+        ];
+        write_hint(&path, &entries).await.unwrap();
+        let read = read_hint(&path).await.unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(
+            (read[0].kind, read[0].key.as_str(), read[0].offset, read[0].len, read[0].expiry),
+            (KIND_PUT, "a", 18, 5, Some(9))
+        );
+        assert_eq!((read[1].kind, read[1].offset, read[1].expiry), (KIND_DELETE, 41, None));
+
+        let contents = fs::read(&path).await.unwrap();
+        for len in 1..HINT_HEADER_LEN + 1 {
+            fs::write(&path, &contents[..len]).await.unwrap();
+            let error = read_hint(&path).await.err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "length {}", len);
+        }
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+// Lock-striped hot tier of resident cache entries.
+//
+// Keys are hashed onto a fixed number of shards, each with its own entry map and
+// an eviction policy tracking just the keys resident in that shard, behind a
+// short-lived std Mutex, so requests for keys on
+// different shards never contend. Guards are only handed to synchronous
+// closures and can't be held across an `.await`; encryption, decryption and
+// disk IO all happen outside of them.
+//
+// Writing a key touches both tiers, so each shard also has an async key lock
+// that writers, deletes and promotions hold from the disk step through the
+// resident update. Without it two writes to one key could land on disk in one
+// order and in memory in the other, or a promotion could copy a record back
+// in after a newer write or a delete. Memory-tier hits never take it.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use tokio::sync::{Mutex as AsyncMutex, MutexGuard as AsyncMutexGuard};
+
+use crate::{eviction_policy::EvictionPolicy, CacheEntry};
+
+// In-memory entries plus the running total of the bytes they are charged.
+#[derive(Default)]
+pub(crate) struct ResidentEntries {
+    entries: HashMap<String, CacheEntry>,
+    bytes: u64,
+}
+
+impl ResidentEntries {
+    pub(crate) fn insert(&mut self, key: String, entry: CacheEntry) {
+        self.bytes += entry.memory_size(&key);
+        if let Some(previous) = self.entries.get(&key) {
+            self.bytes -= previous.memory_size(&key);
+        }
+        self.entries.insert(key, entry);
+    }
+
+    pub(crate) fn get_mut(&mut self, key: &str) -> Option<&mut CacheEntry> {
+        self.entries.get_mut(key)
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) -> Option<CacheEntry> {
+        let entry = self.entries.remove(key)?;
+        self.bytes -= entry.memory_size(key);
+        Some(entry)
+    }
+
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&str, &CacheEntry) -> bool) {
+        let bytes = &mut self.bytes;
+        self.entries.retain(|key, entry| {
+            let kept = keep(key, entry);
+            if !kept {
+                *bytes -= entry.memory_size(key);
+            }
+            kept
+        });
+    }
+}
+
+pub(crate) struct Shard {
+    pub(crate) entries: ResidentEntries,
+    pub(crate) policy: Box<dyn EvictionPolicy>,
+}
+
+pub(crate) struct ShardedEntries {
+    shards: Vec<Mutex<Shard>>,
+    key_locks: Vec<AsyncMutex<()>>, // One per shard, see the module comment
+    memory_bytes: AtomicU64,
+    victim_cursor: AtomicUsize,
+}
+
+impl ShardedEntries {
+    pub(crate) fn new(shard_count: usize, new_policy: impl Fn() -> Box<dyn EvictionPolicy>) -> Self {
+        let shards = (0..shard_count.max(1))
+            .map(|_| {
+                Mutex::new(Shard {
+                    entries: ResidentEntries::default(),
+                    policy: new_policy(),
+                })
+            })
+            .collect::<Vec<_>>();
+        ShardedEntries {
+            key_locks: shards.iter().map(|_| AsyncMutex::new(())).collect(),
+            shards,
+            memory_bytes: AtomicU64::new(0),
+            victim_cursor: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    // Runs `f` with the shard owning `key` locked and keeps the global byte count in step.
+    pub(crate) fn with_shard<R>(&self, key: &str, f: impl FnOnce(&mut Shard) -> R) -> R {
+        self.with_shard_at(self.shard_index(key), f)
+    }
+
+    pub(crate) fn with_shard_at<R>(&self, index: usize, f: impl FnOnce(&mut Shard) -> R) -> R {
+        let mut shard = self.shards[index].lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let before = shard.entries.bytes;
+        let result = f(&mut shard);
+        let after = shard.entries.bytes;
+        if after >= before {
+            self.memory_bytes.fetch_add(after - before, Ordering::Relaxed);
+        } else {
+            self.memory_bytes.fetch_sub(before - after, Ordering::Relaxed);
+        }
+        result
+    }
+
+    // Orders the two-tier updates of every key on `key`'s shard. Not reentrant:
+    // nothing that takes it may be called while it is held.
+    pub(crate) async fn lock_key(&self, key: &str) -> AsyncMutexGuard<'_, ()> {
+        self.key_locks[self.shard_index(key)].lock().await
+    }
+
+    pub(crate) fn memory_bytes(&self) -> u64 {
+        self.memory_bytes.load(Ordering::Relaxed)
+    }
+
+    // Drops the in-memory copy of the next victim, taken round-robin across
+    // shards to approximate one global policy. The disk tier keeps its record.
+    pub(crate) fn demote_next(&self) -> Option<String> {
+        for _ in 0..self.shards.len() {
+            let index = self.victim_cursor.fetch_add(1, Ordering::Relaxed) % self.shards.len();
+            let demoted = self.with_shard_at(index, |shard| {
+                let key = shard.policy.victim()?;
+                shard.entries.remove(&key);
+                Some(key)
+            });
+            if demoted.is_some() {
+                return demoted;
+            }
+        }
+        None
+    }
+
+    pub(crate) fn retain(&self, mut keep: impl FnMut(&str, &CacheEntry) -> bool) {
+        for index in 0..self.shards.len() {
+            self.with_shard_at(index, |shard| {
+                let Shard { entries, policy } = shard;
+                entries.retain(|key, entry| {
+                    let kept = keep(key, entry);
+                    if !kept {
+                        policy.on_remove(key);
+                    }
+                    kept
+                });
+            });
+        }
+    }
+
+    // Drops every resident entry and starts each shard with a fresh policy.
+    pub(crate) fn reset(&self, new_policy: impl Fn() -> Box<dyn EvictionPolicy>) {
+        for index in 0..self.shards.len() {
+            self.with_shard_at(index, |shard| {
+                shard.entries = ResidentEntries::default();
+                shard.policy = new_policy();
+            });
+        }
+    }
+
+    pub(crate) fn shard_index(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish() as usize % self.shards.len()
+    }
+}
@@ -1,188 +1,160 @@
-use tokio::sync::Mutex;
-use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use std::io;
-use std::fs::File;
-use std::io::{Read, Write};
-use std::{collections::HashMap, path::PathBuf, sync::Arc, time::{Instant, SystemTime}};
-use serde::{Serialize, Deserialize};
-use async_trait::async_trait;
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct CacheEntry {
-    value: Vec<u8>, // Stored as encrypted data
-    expiry: Option<Instant>,
-    access_count: usize,
-}
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-struct CacheMetrics {
-    hits: u64,
-    misses: u64,
-    evictions: u64,
-    // Add more metrics as needed
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::time;
+
+use crate::{CacheError, DiskCache};
+
+// Counters for both tiers, bumped without locks on the hot path.
+#[derive(Default)]
+pub(crate) struct CacheMetrics {
+    memory_hits: AtomicU64,
+    disk_hits: AtomicU64,
+    misses: AtomicU64,
+    promotions: AtomicU64, // Disk hits copied back into memory
+    demotions: AtomicU64,  // Memory copies dropped while the disk record stays
+    evictions: AtomicU64,  // Keys dropped from disk, and so from the cache entirely
 }
 
+impl CacheMetrics {
+    pub(crate) fn record_memory_hit(&self) {
+        self.memory_hits.fetch_add(1, Ordering::Relaxed);
+    }
 
-#[derive(Debug)]
-enum CacheError {
-    IoError(io::Error),
-    SerializationError(bincode::Error),
-    EncryptionError(String), // Assuming encryption can fail in specific ways
-}
+    pub(crate) fn record_disk_hit(&self) {
+        self.disk_hits.fetch_add(1, Ordering::Relaxed);
+    }
 
-impl From<io::Error> for CacheError {
-    fn from(err: io::Error) -> Self {
-        CacheError::IoError(err)
+    pub(crate) fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
     }
-}
 
-impl From<bincode::Error> for CacheError {
-    fn from(err: bincode::Error) -> Self {
-        CacheError::SerializationError(err)
+    pub(crate) fn record_promotion(&self) {
+        self.promotions.fetch_add(1, Ordering::Relaxed);
     }
-}
 
-struct Config {
-    encryption_key_path: PathBuf,
-    cache_size: usize,
-    eviction_policy: CacheEvictionPolicy,
-}
+    pub(crate) fn record_demotion(&self) {
+        self.demotions.fetch_add(1, Ordering::Relaxed);
+    }
 
-struct EncryptionService {
-    // Encryption logic here
+    pub(crate) fn record_eviction(&self) {
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
-#[async_trait]
-trait Storage {
-    async fn set(&self, key: String, value: Vec<u8>, ttl: Option<Instant>);
-    async fn get(&self, key: &str) -> Option<Vec<u8>>;
-    async fn cleanup(&self);
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct TierStats {
+    pub(crate) capacity_bytes: u64,
+    pub(crate) used_bytes: u64,
+    pub(crate) hits: u64,
 }
 
-enum CacheEvictionPolicy {
-    LRU,
-    LFU,
-    // Add more strategies as needed
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct CacheStats {
+    pub(crate) memory: TierStats,
+    pub(crate) disk: TierStats,
+    pub(crate) misses: u64,
+    pub(crate) promotions: u64,
+    pub(crate) demotions: u64,
+    pub(crate) evictions: u64,
+    pub(crate) io_backend: &'static str, // The one actually in use, after any fallback
 }
 
-
-
-impl EncryptionService {
-    fn new(key_path: &PathBuf) -> Self {
-        // Initialize encryption service
-        EncryptionService {
-            // Encryption initialization logic
+impl CacheStats {
+    // Share of lookups answered by either tier.
+    pub(crate) fn hit_ratio(&self) -> f64 {
+        let hits = self.memory.hits + self.disk.hits;
+        let lookups = hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            hits as f64 / lookups as f64
         }
     }
-
-    fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
-        // Encrypt data
-        vec![] // Placeholder
-    }
-
-    fn decrypt(&self, ciphertext: &[u8]) -> Vec<u8> {
-        // Decrypt data
-        vec![] // Placeholder
-    }
-}
-
-struct DiskCache {
-    map: Arc<Mutex<HashMap<String, CacheEntry>>>,
-    config: Config,
-    encryption_service: EncryptionService,
 }
 
 impl DiskCache {
-    async fn new(config: Config) -> Self {
-        let encryption_service = EncryptionService::new(&config.encryption_key_path);
-        Self {
-            map: Arc::new(Mutex::new(HashMap::new())),
-            config,
-            encryption_service,
+    pub(crate) async fn stats(&self) -> CacheStats {
+        let metrics = &self.metrics;
+        CacheStats {
+            memory: TierStats {
+                capacity_bytes: self.memory_capacity_bytes,
+                used_bytes: self.map.memory_bytes(),
+                hits: metrics.memory_hits.load(Ordering::Relaxed),
+            },
+            disk: TierStats {
+                capacity_bytes: self.disk_capacity_bytes,
+                used_bytes: self.disk_bytes().await, // What the budget is enforced against
+                hits: metrics.disk_hits.load(Ordering::Relaxed),
+            },
+            misses: metrics.misses.load(Ordering::Relaxed),
+            promotions: metrics.promotions.load(Ordering::Relaxed),
+            demotions: metrics.demotions.load(Ordering::Relaxed),
+            evictions: metrics.evictions.load(Ordering::Relaxed),
+            io_backend: self.store.io_backend(),
         }
     }
+}
 
-    async fn load_from_disk(&self) {
-        let path = &self.config.encryption_key_path.join("cache_data.bin");
-        if path.exists() {
-            let mut file = File::open(path).expect("Failed to open cache file");
-            let mut encrypted_data = Vec::new();
-            file.read_to_end(&mut encrypted_data).expect("Failed to read cache file");
-            let decrypted_data = self.encryption_service.decrypt(&encrypted_data);
-            let cache_map: HashMap<String, CacheEntry> = bincode::deserialize(&decrypted_data).expect("Failed to deserialize cache data");
-            
-            let mut map = self.map.lock().await;
-            *map = cache_map;
-        }
-    }
+// Values are raw bytes end to end; string helpers live on DiskCache itself.
+// The binary calls DiskCache directly; only `cleanup` is reached through the trait.
+#[allow(dead_code)]
+#[async_trait]
+pub(crate) trait Storage {
+    async fn set(&self, key: String, value: Vec<u8>, ttl: Option<Duration>) -> Result<(), CacheError>;
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError>;
+    async fn cleanup(&self) -> Result<(), CacheError>;
+}
 
-    async fn save_to_disk(&self) {
-        let path = &self.config.encryption_key_path.join("cache_data.bin");
-        let map = self.map.lock().await;
-        let serialized_data = bincode::serialize(&*map).expect("Failed to serialize cache data");
-        let encrypted_data = self.encryption_service.encrypt(&serialized_data);
-        
-        let mut file = File::create(path).expect("Failed to create cache file");
-        file.write_all(&encrypted_data).expect("Failed to write encrypted cache data");
-    }
-    
+// Variant names are the values accepted in config.json
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub(crate) enum CacheEvictionPolicy {
+    #[default]
+    LRU,
+    LFU,
+    ARC,
+    TwoQ,
+    WTinyLFU,
 }
 
 #[async_trait]
 impl Storage for DiskCache {
-    async fn set(&self, key: String, value: Vec<u8>, ttl: Option<Instant>) {
-        let encrypted_value = self.encryption_service.encrypt(&value);
-        let entry = CacheEntry {
-            value: encrypted_value,
-            expiry: ttl,
-            access_count: 0,
-        };
-
-        let mut map = self.map.lock().await;
-        map.insert(key, entry);
-
-        // Optionally, implement logic to prevent cache from growing indefinitely
-        if map.len() > self.config.cache_size {
-            self.cleanup_least_frequently_used().await;
-        }
+    async fn set(&self, key: String, value: Vec<u8>, ttl: Option<Duration>) -> Result<(), CacheError> {
+        DiskCache::set(self, &key, &value, ttl).await
     }
 
-    async fn get(&self, key: &str) -> Option<Vec<u8>> {
-        let mut map = self.map.lock().await;
-        map.get_mut(key).and_then(|entry| {
-            entry.access_count += 1;
-            entry.expiry.and_then(|expiry| {
-                if expiry > Instant::now() {
-                    Some(self.encryption_service.decrypt(&entry.value))
-                } else {
-                    map.remove(key);
-                    None
-                }
-            })
-        })
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError> {
+        DiskCache::get(self, key).await
     }
 
-    async fn cleanup(&self) {
-        let mut map = self.map.lock().await;
-        map.retain(|_, entry| {
-            entry.expiry.map_or(true, |expiry| expiry > Instant::now())
+    // Reclaims expired entries from the memory tier and tombstones them on disk.
+    async fn cleanup(&self) -> Result<(), CacheError> {
+        let now = Utc::now().timestamp_millis();
+        self.map.retain(|_, entry| {
+            entry.expiry.is_none_or(|expiry| expiry > now)
         });
+        for key in self.store.expired_keys(now).await {
+            self.delete_entry(&key).await?;
+        }
+        Ok(())
     }
+}
 
-    async fn cleanup_least_frequently_used(&self) {
-        let mut map = self.map.lock().await;
-        if map.len() <= self.config.cache_size {
-            return;
-        }
-    
-        let mut entries: Vec<_> = map.iter().collect();
-        // Sort by access count, ascending (least frequently accessed first)
-        entries.sort_by_key(|(_, entry)| entry.access_count);
-    
-        while map.len() > self.config.cache_size && !entries.is_empty() {
-            if let Some((key, _)) = entries.remove(0) {
-                map.remove(key);
-            }
+pub(crate) async fn periodic_cleanup(storage: Arc<DiskCache>, interval: Duration) {
+    let mut interval_timer = time::interval(interval);
+    loop {
+        interval_timer.tick().await;
+        if let Err(e) = Storage::cleanup(&*storage).await {
+            println!("Expiry sweep failed: {:?}", e);
         }
     }
 }
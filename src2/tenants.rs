@@ -0,0 +1,267 @@
+// Per-tenant encryption keys and crypto-shredding.
+//
+// Several customers can share one cache. Entries written through the tenant
+// API are stored under `<tenant>/<key>`, and their data keys are wrapped by
+// that tenant's own key instead of the shared keyring key. The separator is
+// reserved: the shared API rejects keys containing it, so it can neither read
+// nor overwrite a tenant entry, and tenant reads only accept values wrapped by
+// that tenant's key. Tenant keys live in
+// the keyring, sealed under the master key, and are created on a tenant's
+// first write (see encryption_service.rs).
+//
+// Shredding a tenant destroys its key, and from that moment nothing can unwrap
+// the data keys of its values, wherever copies of them sit: the memory tier,
+// the segment store, the write-ahead log, segment snapshots and backup
+// archives, which keep tenant values wrapped even when the archive is
+// encrypted to public keys. The tenant's entries are then deleted, and stale
+// copies in sealed segments stay unreadable until compaction drops them. The
+// tenant's name stays reserved so nothing is ever written under it again.
+// Copies of the key directory taken before shredding (file system snapshots,
+// host backups) still hold the key and must be dealt with outside the cache.
+//
+// Verification checks that the key is gone and that no value in the store or
+// the backup archive that belongs to the tenant can still be decrypted. An
+// archive that can't be opened here leaves the verification inconclusive.
+
+use std::{io, time::Duration};
+
+use serde::Serialize;
+
+use crate::{
+    audit_log::AuditAction,
+    compression,
+    encryption_service::{is_tenant_key, validate_tenant, EncryptionError},
+    envelope::Envelope,
+    CacheError, DiskCache,
+};
+
+pub(crate) const TENANT_SEPARATOR: char = '/';
+
+pub(crate) fn tenant_entry_key(tenant: &str, key: &str) -> String {
+    format!("{}{}{}", tenant, TENANT_SEPARATOR, key)
+}
+
+pub(crate) fn check_shared_key(key: &str) -> Result<(), CacheError> {
+    if key.contains(TENANT_SEPARATOR) {
+        return Err(CacheError::KeyError(format!(
+            "'{}' contains '{}', which is reserved for tenant entries",
+            key, TENANT_SEPARATOR
+        )));
+    }
+    Ok(())
+}
+
+// Fails unless the value is wrapped by `tenant_key`; passes anything when unset.
+pub(crate) fn check_wrapped_by(stored: &[u8], tenant_key: Option<u32>) -> Result<(), CacheError> {
+    match tenant_key {
+        Some(key_id) if wrapping_key_id(stored) != Some(key_id) => Err(CacheError::IntegrityError),
+        _ => Ok(()),
+    }
+}
+
+// Whether a stored value's data key is wrapped by a tenant key.
+pub(crate) fn is_tenant_value(stored: &[u8]) -> bool {
+    wrapping_key_id(stored).is_some_and(is_tenant_key)
+}
+
+fn wrapping_key_id(stored: &[u8]) -> Option<u32> {
+    Envelope::parse(stored).ok().map(|envelope| envelope.key_id)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ShredReport {
+    pub(crate) tenant: String,
+    pub(crate) key_id: u32,
+    pub(crate) entries_deleted: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct ShredVerification {
+    pub(crate) tenant: String,
+    pub(crate) key_destroyed: bool,
+    pub(crate) entries_remaining: u64, // Still in the store, readable or not
+    pub(crate) backup_values: Option<u64>, // None if the archive could not be opened here
+    pub(crate) backup_error: Option<String>, // Why, in that case
+    pub(crate) readable: u64, // Values, live or backed up, that still decrypt
+}
+
+impl ShredVerification {
+    // An archive that could not be checked may still hold readable values, so
+    // it never counts as erased.
+    pub(crate) fn is_erased(&self) -> bool {
+        self.key_destroyed && self.readable == 0 && self.backup_values.is_some()
+    }
+
+    // Nothing readable was found, but the backup archive went unchecked.
+    pub(crate) fn is_inconclusive(&self) -> bool {
+        self.key_destroyed && self.readable == 0 && self.backup_values.is_none()
+    }
+}
+
+impl DiskCache {
+    pub(crate) async fn set_for_tenant(
+        &self,
+        tenant: &str,
+        key: &str,
+        value: &[u8],
+        ttl: Option<Duration>,
+    ) -> Result<(), CacheError> {
+        let entry_key = tenant_entry_key(tenant, key);
+        self.audited(AuditAction::Write, Some(&entry_key), async {
+            let stored = self.encode_tenant_value(tenant, &entry_key, value).await?;
+            self.write_entry(&entry_key, stored, ttl).await
+        })
+        .await
+    }
+
+    pub(crate) async fn get_for_tenant(&self, tenant: &str, key: &str) -> Result<Option<Vec<u8>>, CacheError> {
+        let entry_key = tenant_entry_key(tenant, key);
+        self.audited(AuditAction::Read, Some(&entry_key), async {
+            match self.tenant_read_key(tenant)? {
+                Some(key_id) => self.read_entry(&entry_key, Some(key_id)).await,
+                None => Ok(None), // Nothing was ever written for the tenant
+            }
+        })
+        .await
+    }
+
+    pub(crate) async fn delete_for_tenant(&self, tenant: &str, key: &str) -> Result<(), CacheError> {
+        let entry_key = tenant_entry_key(tenant, key);
+        self.audited(AuditAction::Delete, Some(&entry_key), async {
+            validate_tenant(tenant)?;
+            self.delete_entry(&entry_key).await
+        })
+        .await
+    }
+
+    // The envelope key ID the tenant's values must carry, if it has a key.
+    fn tenant_read_key(&self, tenant: &str) -> Result<Option<u32>, CacheError> {
+        validate_tenant(tenant)?;
+        match self.encryption_service()?.tenant_key_state(tenant) {
+            Some((_, true)) => Err(EncryptionError::ShreddedTenant(tenant.to_string()).into()),
+            Some((key_id, false)) => Ok(Some(key_id)),
+            None => Ok(None),
+        }
+    }
+
+    async fn encode_tenant_value(&self, tenant: &str, entry_key: &str, value: &[u8]) -> Result<Vec<u8>, CacheError> {
+        let encryption = self.encryption_service()?;
+        let value = compression::compress(value, &self.compression)?;
+        Ok(encryption.encrypt_for_tenant(tenant, &value, self.entry_context(entry_key)).await?)
+    }
+
+    // Destroys the tenant's key, then deletes its entries.
+    pub(crate) async fn shred_tenant(&self, tenant: &str) -> Result<ShredReport, CacheError> {
+        self.audited(AuditAction::ShredTenant, Some(tenant), self.destroy_tenant(tenant)).await
+    }
+
+    async fn destroy_tenant(&self, tenant: &str) -> Result<ShredReport, CacheError> {
+        // The key goes first; once it is persisted the data is unrecoverable
+        // even if deleting the entries below is interrupted
+        let key_id = self.encryption_service()?.shred_tenant(tenant).await?;
+
+        let mut entries_deleted = 0;
+        for key in self.store.keys().await {
+            if self.belongs_to(tenant, key_id, &key).await? {
+                self.delete_entry(&key).await?;
+                entries_deleted += 1;
+            }
+        }
+        // Checkpoint so the WAL holds none of the tenant's ciphertexts either
+        self.save_to_disk().await?;
+        Ok(ShredReport {
+            tenant: tenant.to_string(),
+            key_id,
+            entries_deleted,
+        })
+    }
+
+    pub(crate) async fn verify_tenant_shredded(&self, tenant: &str) -> Result<ShredVerification, CacheError> {
+        let (key_id, key_destroyed) = self
+            .encryption_service()?
+            .tenant_key_state(tenant)
+            .ok_or_else(|| EncryptionError::UnknownTenant(tenant.to_string()))?;
+        let mut verification = ShredVerification {
+            tenant: tenant.to_string(),
+            key_destroyed,
+            ..ShredVerification::default()
+        };
+        let prefix = tenant_entry_key(tenant, "");
+
+        for key in self.store.keys().await {
+            if let Some(record) = self.store.get(&key).await.map_err(CacheError::IoError)? {
+                if key.starts_with(&prefix) || wrapping_key_id(&record.value) == Some(key_id) {
+                    verification.entries_remaining += 1;
+                    if self.decode_value(&key, &record.value).is_ok() {
+                        verification.readable += 1;
+                    }
+                }
+            }
+        }
+
+        match self.read_backup_archive().await {
+            Ok(records) => {
+                let mut count = 0;
+                for record in records {
+                    if record.key.starts_with(&prefix) || wrapping_key_id(&record.value) == Some(key_id) {
+                        count += 1;
+                        // Plaintext records need no key at all
+                        if !record.stored_form || self.decode_value(&record.key, &record.value).is_ok() {
+                            verification.readable += 1;
+                        }
+                    }
+                }
+                verification.backup_values = Some(count);
+            }
+            Err(CacheError::IoError(e)) if e.kind() == io::ErrorKind::NotFound => verification.backup_values = Some(0),
+            Err(e) => verification.backup_error = Some(format!("{:?}", e)),
+        }
+        Ok(verification)
+    }
+
+    // Whether an entry sits under the tenant's prefix or is wrapped by its key.
+    async fn belongs_to(&self, tenant: &str, key_id: u32, key: &str) -> Result<bool, CacheError> {
+        if key.starts_with(&tenant_entry_key(tenant, "")) {
+            return Ok(true);
+        }
+        let record = self.store.get(key).await.map_err(CacheError::IoError)?;
+        Ok(record.is_some_and(|record| wrapping_key_id(&record.value) == Some(key_id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup_and_recovery::BACKUP_ARCHIVE_FILE;
+
+    #[tokio::test]
+    async fn shredding_leaves_nothing_readable() {
+        let cache = crate::tests::test_cache("tenant-shred", |_| {}).await;
+        cache.set_for_tenant("acme", "key", b"value", None).await.unwrap();
+        cache.set("shared", b"value", None).await.unwrap();
+
+        let report = cache.shred_tenant("acme").await.unwrap();
+        assert_eq!(report.entries_deleted, 1);
+        let verification = cache.verify_tenant_shredded("acme").await.unwrap();
+        assert!(verification.is_erased());
+        assert_eq!(verification.backup_values, Some(0)); // No archive was ever written
+        assert!(matches!(cache.get_for_tenant("acme", "key").await, Err(CacheError::KeyError(_))));
+        assert_eq!(cache.get("shared").await.unwrap().as_deref(), Some(&b"value"[..]));
+        std::fs::remove_dir_all(&cache.cache_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn an_unreadable_archive_is_inconclusive() {
+        let cache = crate::tests::test_cache("tenant-unchecked-backup", |_| {}).await;
+        cache.set_for_tenant("acme", "key", b"value", None).await.unwrap();
+        cache.shred_tenant("acme").await.unwrap();
+        std::fs::write(cache.cache_dir.join(BACKUP_ARCHIVE_FILE), b"not an archive").unwrap();
+
+        let verification = cache.verify_tenant_shredded("acme").await.unwrap();
+        assert_eq!(verification.backup_values, None);
+        assert!(verification.backup_error.is_some());
+        assert!(!verification.is_erased());
+        assert!(verification.is_inconclusive());
+        std::fs::remove_dir_all(&cache.cache_dir).unwrap();
+    }
+}
@@ -0,0 +1,294 @@
+// Write-ahead log for DiskCache.
+//
+// Writes and deletes are appended here before they reach the segment store, so
+// anything acknowledged under the configured fsync policy survives a crash that
+// happens before the next `save_to_disk`. Saving checkpoints the log by
+// truncating it once the segment store itself has been fsynced, and startup
+// replays whatever is left on top of the store.
+//
+// Each record is the segment store's record followed by a CRC32C of it. Replay
+// stops at the first record whose checksum does not match and truncates the
+// log there, as it does for a torn tail; a log written before the checksum was
+// added therefore replays nothing. Records too large for their u32 lengths are
+// refused before anything is written.
+//
+// Record layout (little-endian):
+//
+//     kind: u8 | expiry_unix_ms: i64 | key_len: u32 | value_len: u32 | key | value | crc32c: u32
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+    time,
+};
+
+use crate::segment_store::check_record_lens;
+
+const RECORD_HEADER_LEN: usize = 17;
+const CHECKSUM_LEN: usize = 4;
+
+const KIND_PUT: u8 = 1;
+const KIND_DELETE: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FsyncPolicy {
+    Always,           // fsync before every write is acknowledged
+    EveryMillis(u64), // fsync from a background task at this interval
+    Never,            // hand writes to the OS and let it decide
+}
+
+impl Default for FsyncPolicy {
+    fn default() -> Self {
+        FsyncPolicy::EveryMillis(100)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum WalRecord {
+    Put { key: String, value: Vec<u8>, expiry: Option<i64> },
+    Delete { key: String },
+}
+
+struct WalFile {
+    file: File,
+    unsynced: bool,
+}
+
+pub(crate) struct WriteAheadLog {
+    path: PathBuf,
+    policy: FsyncPolicy,
+    file: Mutex<WalFile>,
+    bytes: AtomicU64, // Length of the log file, counted against the disk budget
+}
+
+impl WriteAheadLog {
+    pub(crate) async fn open(path: &Path, policy: FsyncPolicy) -> io::Result<Arc<Self>> {
+        let file = OpenOptions::new().create(true).append(true).open(path).await?;
+        let bytes = file.metadata().await?.len();
+        let wal = Arc::new(WriteAheadLog {
+            path: path.to_path_buf(),
+            policy,
+            file: Mutex::new(WalFile { file, unsynced: false }),
+            bytes: AtomicU64::new(bytes),
+        });
+
+        if let FsyncPolicy::EveryMillis(interval) = policy {
+            tokio::spawn(periodic_sync(Arc::downgrade(&wal), Duration::from_millis(interval.max(1))));
+        }
+        Ok(wal)
+    }
+
+    pub(crate) async fn append_put(&self, key: &str, value: &[u8], expiry: Option<i64>) -> io::Result<()> {
+        self.append(KIND_PUT, key, value, expiry).await
+    }
+
+    pub(crate) async fn append_delete(&self, key: &str) -> io::Result<()> {
+        self.append(KIND_DELETE, key, &[], None).await
+    }
+
+    async fn append(&self, kind: u8, key: &str, value: &[u8], expiry: Option<i64>) -> io::Result<()> {
+        check_record_lens(key.len(), value.len())?;
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + key.len() + value.len() + CHECKSUM_LEN);
+        record.push(kind);
+        record.extend_from_slice(&expiry.unwrap_or(0).to_le_bytes());
+        record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        record.extend_from_slice(key.as_bytes());
+        record.extend_from_slice(value);
+        let checksum = crc32c::crc32c(&record);
+        record.extend_from_slice(&checksum.to_le_bytes());
+
+        let mut wal = self.file.lock().await;
+        wal.file.write_all(&record).await?;
+        wal.file.flush().await?;
+        self.bytes.fetch_add(record.len() as u64, Ordering::Relaxed);
+        match self.policy {
+            FsyncPolicy::Always => wal.file.sync_data().await?,
+            FsyncPolicy::EveryMillis(_) | FsyncPolicy::Never => wal.unsynced = true,
+        }
+        Ok(())
+    }
+
+    pub(crate) fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) async fn sync(&self) -> io::Result<()> {
+        let mut wal = self.file.lock().await;
+        if wal.unsynced {
+            wal.file.sync_data().await?;
+            wal.unsynced = false;
+        }
+        Ok(())
+    }
+
+    // Reads back every complete record, truncating a torn or damaged record and
+    // everything after it.
+    pub(crate) async fn replay(&self) -> io::Result<Vec<WalRecord>> {
+        let wal = self.file.lock().await;
+        let buffer = fs::read(&self.path).await?;
+
+        let mut records = Vec::new();
+        let mut cursor = 0usize;
+        while let Some(header) = buffer.get(cursor..cursor + RECORD_HEADER_LEN) {
+            let kind = header[0];
+            let expiry = match i64::from_le_bytes(header[1..9].try_into().unwrap()) {
+                0 => None,
+                expiry => Some(expiry),
+            };
+            let key_len = u32::from_le_bytes(header[9..13].try_into().unwrap()) as usize;
+            let value_len = u32::from_le_bytes(header[13..17].try_into().unwrap()) as usize;
+
+            let body_start = cursor + RECORD_HEADER_LEN;
+            let body_end = body_start + key_len + value_len;
+            let checksum = match buffer.get(body_end..body_end + CHECKSUM_LEN) {
+                Some(checksum) => u32::from_le_bytes(checksum.try_into().unwrap()),
+                None => break,
+            };
+            if crc32c::crc32c(&buffer[cursor..body_end]) != checksum {
+                break;
+            }
+            let key = match String::from_utf8(buffer[body_start..body_start + key_len].to_vec()) {
+                Ok(key) => key,
+                Err(_) => break,
+            };
+            let value = buffer[body_start + key_len..body_end].to_vec();
+
+            match kind {
+                KIND_PUT => records.push(WalRecord::Put { key, value, expiry }),
+                KIND_DELETE => records.push(WalRecord::Delete { key }),
+                _ => break,
+            }
+            cursor = body_end + CHECKSUM_LEN;
+        }
+
+        if cursor < buffer.len() {
+            wal.file.set_len(cursor as u64).await?;
+            self.bytes.store(cursor as u64, Ordering::Relaxed);
+        }
+        Ok(records)
+    }
+
+    // Discards the log; callers must have made every logged write durable elsewhere first.
+    pub(crate) async fn checkpoint(&self) -> io::Result<()> {
+        let mut wal = self.file.lock().await;
+        wal.file.set_len(0).await?;
+        self.bytes.store(0, Ordering::Relaxed);
+        wal.file.sync_all().await?;
+        wal.unsynced = false;
+        Ok(())
+    }
+}
+
+async fn periodic_sync(wal: Weak<WriteAheadLog>, interval: Duration) {
+    let mut interval_timer = time::interval(interval);
+    loop {
+        interval_timer.tick().await;
+        let wal = match wal.upgrade() {
+            Some(wal) => wal,
+            None => break, // Cache dropped, stop syncing
+        };
+        if let Err(e) = wal.sync().await {
+            println!("Failed to fsync write-ahead log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("trust-wal-{}-{}", std::process::id(), name))
+    }
+
+    async fn write_log(path: &Path) {
+        let wal = WriteAheadLog::open(path, FsyncPolicy::Never).await.unwrap();
+        wal.append_put("a", b"first", Some(42)).await.unwrap();
+        wal.append_delete("a").await.unwrap();
+        wal.append_put("b", b"second", None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn round_trips() {
+        let path = test_path("round-trip");
+        let _ = fs::remove_file(&path).await;
+        write_log(&path).await;
+
+        let records = WriteAheadLog::open(&path, FsyncPolicy::Never).await.unwrap().replay().await.unwrap();
+        assert_eq!(records.len(), 3);
+        assert!(matches!(
+            &records[0],
+            WalRecord::Put { key, value, expiry: Some(42) } if key == "a" && value == b"first"
+        ));
+        assert!(matches!(&records[1], WalRecord::Delete { key } if key == "a"));
+        assert!(matches!(
+            &records[2],
+            WalRecord::Put { key, value, expiry: None } if key == "b" && value == b"second"
+        ));
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn drops_a_torn_tail() {
+        let path = test_path("torn");
+        let _ = fs::remove_file(&path).await;
+        write_log(&path).await;
+        let contents = fs::read(&path).await.unwrap();
+        let intact_len = contents.len() - (RECORD_HEADER_LEN + 1 + 6 + CHECKSUM_LEN);
+
+        for len in intact_len..contents.len() {
+            fs::write(&path, &contents[..len]).await.unwrap();
+            let wal = WriteAheadLog::open(&path, FsyncPolicy::Never).await.unwrap();
+            assert_eq!(wal.replay().await.unwrap().len(), 2, "length {}", len);
+            assert_eq!(fs::metadata(&path).await.unwrap().len(), intact_len as u64);
+        }
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stops_at_an_unknown_record_kind() {
+        let path = test_path("unknown-kind");
+        let _ = fs::remove_file(&path).await;
+        write_log(&path).await;
+        let mut contents = fs::read(&path).await.unwrap();
+        let second = RECORD_HEADER_LEN + 1 + 5 + CHECKSUM_LEN;
+        contents[second] = 0xFF;
+        fs::write(&path, &contents).await.unwrap();
+
+        let records = WriteAheadLog::open(&path, FsyncPolicy::Never).await.unwrap().replay().await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(fs::metadata(&path).await.unwrap().len(), second as u64);
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stops_at_a_checksum_mismatch() {
+        let path = test_path("checksum");
+        let _ = fs::remove_file(&path).await;
+        write_log(&path).await;
+        let mut contents = fs::read(&path).await.unwrap();
+        let second = RECORD_HEADER_LEN + 1 + 5 + CHECKSUM_LEN;
+        let third = second + RECORD_HEADER_LEN + 1 + CHECKSUM_LEN;
+        contents[third + RECORD_HEADER_LEN + 1] ^= 0x01; // A bit of "second"
+        fs::write(&path, &contents).await.unwrap();
+
+        let records = WriteAheadLog::open(&path, FsyncPolicy::Never).await.unwrap().replay().await.unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(fs::metadata(&path).await.unwrap().len(), third as u64);
+        fs::remove_file(&path).await.unwrap();
+    }
+}